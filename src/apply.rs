@@ -0,0 +1,558 @@
+//! Applying a duplicate report.
+//! Duplicates are moved into a quarantine folder rather than deleted, keeping their place in the
+//! original tree, and every move is recorded in a journal so that it can be undone. Alternatively
+//! exact duplicates can be replaced by links to their keeper, which frees the space they take
+//! while keeping every path.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::str::FromStr;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::exact::{file_hash, same_contents};
+use crate::report::DuplicateReport;
+use crate::xmp::sidecar_path;
+
+/// Name of the journal inside the quarantine folder.
+pub const JOURNAL_FILE: &str = "journal.json";
+
+/// How an exact duplicate is replaced by its keeper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Another name for the keeper's data, so changing one file changes both.
+    Hardlink,
+    /// A copy-on-write clone of the keeper, on file systems that support it such as Btrfs and
+    /// XFS. Falls back to a hardlink elsewhere.
+    Reflink,
+}
+
+impl FromStr for LinkMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "reflink" => Ok(LinkMode::Reflink),
+            _ => Err(AppError::UnknownLinkMode(s.to_string())),
+        }
+    }
+}
+
+/// A duplicate replaced by a link to its keeper.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedFile {
+    pub path: PathBuf,
+    pub keeper: PathBuf,
+    /// The kind of link made, which is a hardlink if a reflink wasn't possible.
+    pub mode: LinkMode,
+}
+
+/// The files moved into a quarantine folder.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Journal {
+    pub moves: Vec<MovedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MovedFile {
+    /// Absolute path the file was moved from.
+    pub from: PathBuf,
+    /// Path in the quarantine folder, relative to it.
+    pub to: PathBuf,
+    /// Hash of the contents, checked before the file is restored.
+    pub content_hash: u64,
+}
+
+impl Journal {
+    /// Read the journal of a quarantine folder, which is empty if nothing has been moved there.
+    pub fn open(quarantine: &Path) -> Result<Journal, AppError> {
+        let path = quarantine.join(JOURNAL_FILE);
+        if !path.exists() {
+            return Ok(Journal::default());
+        }
+
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Write the journal into the quarantine folder, removing it once it is empty.
+    pub fn save(&self, quarantine: &Path) -> Result<(), AppError> {
+        let path = quarantine.join(JOURNAL_FILE);
+        if self.moves.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+
+        // Write to a temporary file first so that an interrupted save can't lose earlier moves
+        fs::create_dir_all(quarantine)?;
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.into_inner().map_err(|e| e.into_error())?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+/// Where a file is kept in a quarantine folder, relative to it: the file's absolute path without
+/// its root. A Windows drive or share becomes the first folder, e.g. `C\photos\a.jpg`.
+pub fn quarantine_path(file: &Path) -> Result<PathBuf, AppError> {
+    Ok(relative_path(&fs::canonicalize(file)?))
+}
+
+// An absolute path made relative, keeping any prefix so that files on different drives differ
+fn relative_path(path: &Path) -> PathBuf {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(disk) | Prefix::VerbatimDisk(disk) => {
+                    relative.push((disk as char).to_string())
+                }
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    relative.push(server);
+                    relative.push(share);
+                }
+                Prefix::Verbatim(name) | Prefix::DeviceNS(name) => relative.push(name),
+            },
+            Component::Normal(name) => relative.push(name),
+            Component::RootDir | Component::CurDir | Component::ParentDir => {}
+        }
+    }
+    relative
+}
+
+/// Move the duplicates of every group, with their sidecars and companions, into `quarantine`.
+/// Groups whose keeper no longer exists are left alone. Moves are added to the folder's journal as
+/// they are made, so the journal is complete even if applying fails part way.
+pub fn apply_report(report: &DuplicateReport, quarantine: &Path) -> Result<Journal, AppError> {
+    let mut journal = Journal::open(quarantine)?;
+    let mut applied = Journal::default();
+
+    for group in &report.groups {
+        if !group.keeper.exists() {
+            warn!(
+                "Skipping group of missing keeper {}",
+                group.keeper.display()
+            );
+            continue;
+        }
+
+        // A sidecar shared with the keeper, e.g. by photo.jpg and photo.png, stays with it
+        let mut kept: HashSet<PathBuf> = HashSet::from([sidecar_path(&group.keeper)]);
+
+        for file in group.duplicates() {
+            if !file.path.exists() {
+                warn!("Skipping missing file {}", file.path.display());
+                continue;
+            }
+
+            // The whole shot goes, e.g. a JPEG with its RAW file, each with its sidecar
+            let mut paths = Vec::new();
+            for path in std::iter::once(&file.path).chain(&file.companions) {
+                if !path.exists() {
+                    warn!("Skipping missing file {}", path.display());
+                    continue;
+                }
+                paths.push(path.clone());
+
+                let sidecar = sidecar_path(path);
+                if sidecar != *path && sidecar.exists() && kept.insert(sidecar.clone()) {
+                    paths.push(sidecar);
+                }
+            }
+
+            for from in paths {
+                let moved = match quarantine_file(quarantine, &from) {
+                    Ok(moved) => moved,
+                    Err(err) => {
+                        // Keep the moves already made in this group undoable
+                        journal.save(quarantine)?;
+                        return Err(err);
+                    }
+                };
+                journal.moves.push(moved.clone());
+                applied.moves.push(moved);
+            }
+        }
+
+        journal.save(quarantine)?;
+    }
+
+    Ok(applied)
+}
+
+/// Move every file in the quarantine folder's journal back to where it came from. Files that are
+/// missing, were modified or whose original path is taken again are left in quarantine and kept
+/// in the journal. Returns the files restored.
+pub fn undo(quarantine: &Path) -> Result<Journal, AppError> {
+    let journal = Journal::open(quarantine)?;
+    let mut remaining = Journal::default();
+    let mut restored = Journal::default();
+
+    for moved in journal.moves.into_iter().rev() {
+        match restore_file(quarantine, &moved) {
+            Ok(()) => restored.moves.push(moved),
+            Err(e) => {
+                warn!("Can't restore {}: {}", moved.from.display(), e);
+                remaining.moves.push(moved);
+            }
+        }
+    }
+
+    remaining.moves.reverse();
+    remaining.save(quarantine)?;
+
+    Ok(restored)
+}
+
+/// Replace the duplicates that are byte-identical to their keeper with links to it. Files that
+/// differ from the keeper, are already linked to it or can't be linked are left as they are.
+pub fn link_report(report: &DuplicateReport, mode: LinkMode) -> Result<Vec<LinkedFile>, AppError> {
+    let mut linked = Vec::new();
+
+    for group in &report.groups {
+        let Ok(keeper_size) = fs::metadata(&group.keeper).map(|m| m.len()) else {
+            warn!(
+                "Skipping group of missing keeper {}",
+                group.keeper.display()
+            );
+            continue;
+        };
+
+        for file in group.duplicates() {
+            match fs::metadata(&file.path) {
+                Ok(metadata) if metadata.len() == keeper_size => {}
+                _ => continue,
+            }
+            if is_same_file(&group.keeper, &file.path)? {
+                continue;
+            }
+
+            match link_file(&group.keeper, &file.path, mode) {
+                Ok(Some(mode)) => linked.push(LinkedFile {
+                    path: file.path.clone(),
+                    keeper: group.keeper.clone(),
+                    mode,
+                }),
+                Ok(None) => debug!("Not an exact duplicate: {}", file.path.display()),
+                Err(e) => warn!("Can't link {}: {}", file.path.display(), e),
+            }
+        }
+    }
+
+    Ok(linked)
+}
+
+// Replace `path` with a link to `keeper` if their contents are identical. The link is made next
+// to the file and renamed over it, so the file is never missing.
+fn link_file(keeper: &Path, path: &Path, mode: LinkMode) -> Result<Option<LinkMode>, AppError> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".deduper-link");
+    let tmp = path.with_file_name(name);
+
+    let used = match mode {
+        LinkMode::Reflink => match reflink(keeper, &tmp) {
+            Ok(()) => LinkMode::Reflink,
+            Err(e) => {
+                debug!("Can't reflink {}, hardlinking: {}", path.display(), e);
+                fs::hard_link(keeper, &tmp)?;
+                LinkMode::Hardlink
+            }
+        },
+        LinkMode::Hardlink => {
+            fs::hard_link(keeper, &tmp)?;
+            LinkMode::Hardlink
+        }
+    };
+
+    // Compared as late as possible so that a file modified since the scan is never replaced
+    let result = same_contents(keeper, path).and_then(|same| {
+        if same {
+            fs::rename(&tmp, path)?;
+        }
+        Ok(same)
+    });
+    if !matches!(result, Ok(true)) {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    Ok(result?.then_some(used))
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = File::open(from)?;
+    let clone = File::create_new(to)?;
+    // SAFETY: both file descriptors stay open for the duration of the call
+    let result = unsafe { libc::ioctl(clone.as_raw_fd(), libc::FICLONE as _, source.as_raw_fd()) };
+    if result == -1 {
+        let error = io::Error::last_os_error();
+        drop(clone);
+        let _ = fs::remove_file(to);
+        return Err(error);
+    }
+    fs::set_permissions(to, source.metadata()?.permissions())?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> Result<bool, AppError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> Result<bool, AppError> {
+    Ok(false)
+}
+
+fn quarantine_file(quarantine: &Path, from: &Path) -> Result<MovedFile, AppError> {
+    let from = fs::canonicalize(from)?;
+    let to = quarantine_path(&from)?;
+    let destination = quarantine.join(&to);
+    if destination.exists() {
+        return Err(AppError::FileExists(destination));
+    }
+
+    let content_hash = file_hash(&from)?;
+    debug!("Moving {} to {}", from.display(), destination.display());
+    move_file(&from, &destination)?;
+
+    Ok(MovedFile {
+        from,
+        to,
+        content_hash,
+    })
+}
+
+fn restore_file(quarantine: &Path, moved: &MovedFile) -> Result<(), AppError> {
+    if moved.from.exists() {
+        return Err(AppError::FileExists(moved.from.clone()));
+    }
+    let source = quarantine.join(&moved.to);
+    if file_hash(&source)? != moved.content_hash {
+        return Err(AppError::ContentChanged(source));
+    }
+
+    debug!("Restoring {}", moved.from.display());
+    move_file(&source, &moved.from)?;
+
+    // Clear away the folders that mirrored the original tree
+    for dir in moved.to.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::remove_dir(quarantine.join(dir)).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Rename the file, falling back to copying it when the destination is on another file system
+fn move_file(from: &Path, to: &Path) -> Result<(), AppError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+        }
+        result => result?,
+    }
+
+    Ok(())
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{DuplicateGroup, ReportedFile};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_apply_and_undo() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let quarantine = dir.join("quarantine");
+        let keeper = dir.join("photos/keeper.jpg");
+        let copy = dir.join("photos/copy.jpg");
+        let other = dir.join("backup/copy.png");
+        for path in [&keeper, &copy, &other] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, path.to_string_lossy().as_bytes()).unwrap();
+        }
+        fs::write(sidecar_path(&keeper), "keeper sidecar").unwrap();
+        fs::write(sidecar_path(&copy), "copy sidecar").unwrap();
+
+        let raw = copy.with_extension("nef");
+        fs::write(&raw, "raw").unwrap();
+
+        let mut report = report(vec![&keeper, &copy, &other]);
+        report.groups[0].files[1].companions = vec![raw.clone()];
+        let applied = apply_report(&report, &quarantine).unwrap();
+
+        // The copy, its sidecar, shared with its RAW file, the RAW file and the other copy
+        assert_eq!(applied.moves.len(), 4);
+        assert!(!raw.exists());
+        assert!(keeper.exists() && sidecar_path(&keeper).exists());
+        assert!(!copy.exists() && !sidecar_path(&copy).exists() && !other.exists());
+        let quarantined = quarantine
+            .join(quarantine_path(dir).unwrap())
+            .join("photos/copy.jpg");
+        assert_eq!(
+            fs::read_to_string(&quarantined).unwrap(),
+            copy.to_string_lossy()
+        );
+        assert_eq!(Journal::open(&quarantine).unwrap(), applied);
+
+        let restored = undo(&quarantine).unwrap();
+
+        assert_eq!(restored.moves.len(), 4);
+        assert!(raw.exists());
+        assert_eq!(fs::read_to_string(&copy).unwrap(), copy.to_string_lossy());
+        assert_eq!(
+            fs::read_to_string(sidecar_path(&copy)).unwrap(),
+            "copy sidecar"
+        );
+        assert!(other.exists());
+        assert!(!quarantine.join(JOURNAL_FILE).exists());
+        assert_eq!(fs::read_dir(&quarantine).unwrap().count(), 0);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_drives_are_kept_apart() {
+        assert_eq!(
+            relative_path(Path::new(r"C:\photos\a.jpg")),
+            Path::new(r"C\photos\a.jpg")
+        );
+        assert_eq!(
+            relative_path(Path::new(r"\\?\D:\photos\a.jpg")),
+            Path::new(r"D\photos\a.jpg")
+        );
+        assert_eq!(
+            relative_path(Path::new(r"\\nas\share\a.jpg")),
+            Path::new(r"nas\share\a.jpg")
+        );
+    }
+
+    #[test]
+    fn test_undo_keeps_conflicting_files() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let quarantine = dir.join("quarantine");
+        let keeper = dir.join("keeper.jpg");
+        let copy = dir.join("copy.jpg");
+        fs::write(&keeper, "keeper").unwrap();
+        fs::write(&copy, "copy").unwrap();
+
+        apply_report(&report(vec![&keeper, &copy]), &quarantine).unwrap();
+        fs::write(&copy, "new file").unwrap();
+
+        assert!(undo(&quarantine).unwrap().moves.is_empty());
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "new file");
+        assert_eq!(Journal::open(&quarantine).unwrap().moves.len(), 1);
+
+        fs::remove_file(&copy).unwrap();
+        assert_eq!(undo(&quarantine).unwrap().moves.len(), 1);
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "copy");
+    }
+
+    #[test]
+    fn test_undo_after_failed_apply() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let quarantine = dir.join("quarantine");
+        let keeper = dir.join("keeper.jpg");
+        let first = dir.join("first.jpg");
+        let second = dir.join("second.jpg");
+        for path in [&keeper, &first, &second] {
+            fs::write(path, path.to_string_lossy().as_bytes()).unwrap();
+        }
+
+        // The second move of the group fails as its destination is taken
+        let taken = quarantine.join(quarantine_path(&second).unwrap());
+        fs::create_dir_all(taken.parent().unwrap()).unwrap();
+        fs::write(&taken, "taken").unwrap();
+
+        let result = apply_report(&report(vec![&keeper, &first, &second]), &quarantine);
+        assert!(matches!(result, Err(AppError::FileExists(_))));
+        assert!(!first.exists() && second.exists());
+
+        assert_eq!(undo(&quarantine).unwrap().moves.len(), 1);
+        assert_eq!(fs::read_to_string(&first).unwrap(), first.to_string_lossy());
+    }
+
+    #[test]
+    fn test_link_exact_duplicates() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let keeper = dir.join("keeper.jpg");
+        let copy = dir.join("copy.jpg");
+        let clone = dir.join("clone.jpg");
+        let similar = dir.join("similar.jpg");
+        fs::write(&keeper, "image").unwrap();
+        fs::write(&copy, "image").unwrap();
+        fs::write(&clone, "image").unwrap();
+        fs::write(&similar, "imagf").unwrap();
+
+        let first = report(vec![&keeper, &copy, &similar]);
+        let linked = link_report(&first, LinkMode::Hardlink).unwrap();
+
+        assert_eq!(linked.len(), 1);
+        assert_eq!(linked[0].path, copy);
+        assert!(is_same_file(&keeper, &copy).unwrap());
+        assert_eq!(fs::read_to_string(&similar).unwrap(), "imagf");
+        assert!(link_report(&first, LinkMode::Hardlink).unwrap().is_empty());
+
+        // Reflinks fall back to hardlinks on file systems without them
+        let linked =
+            link_report(&report(vec![&keeper, &clone]), "reflink".parse().unwrap()).unwrap();
+        assert_eq!(linked.len(), 1);
+        assert_eq!(fs::read_to_string(&clone).unwrap(), "image");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 4);
+    }
+
+    // A report with one group, the first path being the keeper
+    fn report(paths: Vec<&PathBuf>) -> DuplicateReport {
+        DuplicateReport::new(vec![DuplicateGroup {
+            keeper: paths[0].clone(),
+            files: paths
+                .into_iter()
+                .map(|path| ReportedFile {
+                    path: path.clone(),
+                    size: 0,
+                    resolution: None,
+                    score: None,
+                    date_time_original: None,
+                    sidecar: false,
+                    companions: Vec::new(),
+                    motion: false,
+                    root: None,
+                })
+                .collect(),
+        }])
+    }
+}
@@ -0,0 +1,495 @@
+//! Persistent cache of image and video fingerprints and similarity scores.
+//! Decoding is by far the most expensive part of a scan, so everything derived from the pixels
+//! is stored in a single JSON file and reused until the file changes.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use log::{debug, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::CancellationToken;
+use crate::error::AppError;
+use crate::exact::file_hash;
+use crate::exif::ExifSummary;
+use crate::fingerprint::Fingerprints;
+use crate::image::{hash_pixels, HashMode, Image, Orientation};
+use crate::progress::{Phase, Progress};
+use crate::similarity::SsimMetric;
+use crate::video::Video;
+
+/// Bumped whenever the layout of the cache file or the meaning of its values changes.
+const CACHE_VERSION: u32 = 5;
+
+/// How often `checkpoint` saves the cache during a scan.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything the duplicate search needs to know about one file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    /// xxh3 hash of the file contents.
+    pub content_hash: u64,
+    /// xxh3 hash of the decoded, orientation-normalised pixels.
+    pub pixel_hash: u64,
+    /// Resolution after applying the orientation.
+    pub resolution: (u32, u32),
+    pub orientation: Orientation,
+    pub exif: Option<ExifSummary>,
+    pub fingerprints: Fingerprints,
+}
+
+impl CacheEntry {
+    pub fn aspect_ratio(&self) -> f32 {
+        self.resolution.0 as f32 / self.resolution.1 as f32
+    }
+}
+
+/// The frame fingerprints of a video, which take several runs of `ffmpeg` to compute.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VideoEntry {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    /// The fingerprints of each frame, as `Video::fingerprints` computes them.
+    pub fingerprints: Vec<Fingerprints>,
+}
+
+#[derive(Deserialize)]
+struct CacheHeader {
+    #[serde(default)]
+    version: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FingerprintCache {
+    version: u32,
+    entries: HashMap<PathBuf, CacheEntry>,
+    videos: HashMap<PathBuf, VideoEntry>,
+    /// Similarity scores keyed by the content hashes of both files and the metric.
+    scores: HashMap<String, f32>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// When `checkpoint` last saved the cache, or when it was opened.
+    #[serde(skip)]
+    saved_at: Option<Instant>,
+}
+
+impl FingerprintCache {
+    /// A cache that is never written to disk.
+    pub fn in_memory() -> FingerprintCache {
+        FingerprintCache {
+            version: CACHE_VERSION,
+            ..FingerprintCache::default()
+        }
+    }
+
+    /// Load the cache stored at `path`. A missing or outdated file gives an empty cache.
+    pub fn open(path: &Path) -> Result<FingerprintCache, AppError> {
+        let mut cache = if path.exists() {
+            // Check the version before parsing entries whose layout may have changed
+            let contents = fs::read_to_string(path)?;
+            let header: CacheHeader = serde_json::from_str(&contents)?;
+            if header.version == CACHE_VERSION {
+                serde_json::from_str(&contents)?
+            } else {
+                warn!("Discarding outdated cache {}", path.display());
+                FingerprintCache::in_memory()
+            }
+        } else {
+            FingerprintCache::in_memory()
+        };
+
+        debug!(
+            "Loaded {} cache entries from {}",
+            cache.entries.len(),
+            path.display()
+        );
+        cache.path = Some(path.to_path_buf());
+        cache.saved_at = Some(Instant::now());
+
+        Ok(cache)
+    }
+
+    /// Write the cache back to the file it was opened from.
+    pub fn save(&self) -> Result<(), AppError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        // Write to a temporary file first so that an interrupted save can't corrupt the cache
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner().map_err(|e| e.into_error())?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Save the cache if it hasn't been saved for `CHECKPOINT_INTERVAL`, so that an interrupted
+    /// scan can resume from here.
+    pub fn checkpoint(&mut self) -> Result<(), AppError> {
+        let now = Instant::now();
+        if self
+            .saved_at
+            .is_none_or(|saved_at| now.duration_since(saved_at) >= CHECKPOINT_INTERVAL)
+        {
+            self.save()?;
+            self.saved_at = Some(now);
+        }
+
+        Ok(())
+    }
+
+    /// The file the cache is saved to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the cached entry for `path`, computing it if the file is new or has changed.
+    pub fn get(&mut self, path: &Path) -> Result<&CacheEntry, AppError> {
+        let entry = self.lookup(path)?;
+        self.entries.insert(path.to_path_buf(), entry);
+
+        Ok(&self.entries[path])
+    }
+
+    /// Returns the entries of `paths` in the same order, computing those of new or changed files
+    /// in parallel. Each file advances the fingerprinting phase of `progress`. Once `cancel` is
+    /// cancelled, the files not yet started give `AppError::Cancelled`.
+    pub fn get_all(
+        &mut self,
+        paths: &[PathBuf],
+        progress: &dyn Progress,
+        cancel: &CancellationToken,
+    ) -> Vec<Result<CacheEntry, AppError>> {
+        let entries: Vec<_> = paths
+            .par_iter()
+            .map(|path| {
+                cancel.check()?;
+                let entry = self.lookup(path);
+                progress.advance(Phase::Fingerprinting, 1);
+                entry
+            })
+            .collect();
+
+        for (path, entry) in paths.iter().zip(&entries) {
+            if let Ok(entry) = entry {
+                self.entries.insert(path.clone(), entry.clone());
+            }
+        }
+
+        entries
+    }
+
+    /// Returns the frame fingerprints of `videos`, each given with its duration, in the same
+    /// order, computing those of new or changed files in parallel. Each video advances the video
+    /// phase of `progress`. Once `cancel` is cancelled, the videos not yet started give
+    /// `AppError::Cancelled`.
+    pub fn get_all_videos(
+        &mut self,
+        videos: &[(PathBuf, f64)],
+        progress: &dyn Progress,
+        cancel: &CancellationToken,
+    ) -> Vec<Result<Vec<Fingerprints>, AppError>> {
+        let entries: Vec<_> = videos
+            .par_iter()
+            .map(|(path, duration)| {
+                cancel.check()?;
+                let entry = self.lookup_video(path, *duration);
+                progress.advance(Phase::Videos, 1);
+                entry
+            })
+            .collect();
+
+        entries
+            .into_iter()
+            .zip(videos)
+            .map(|(entry, (path, _))| {
+                let entry = entry?;
+                let fingerprints = entry.fingerprints.clone();
+                self.videos.insert(path.clone(), entry);
+                Ok(fingerprints)
+            })
+            .collect()
+    }
+
+    /// Returns the stored similarity score of two files.
+    pub fn score(
+        &self,
+        entry1: &CacheEntry,
+        entry2: &CacheEntry,
+        metric: SsimMetric,
+    ) -> Option<f32> {
+        self.scores.get(&score_key(entry1, entry2, metric)).copied()
+    }
+
+    pub fn insert_score(
+        &mut self,
+        entry1: &CacheEntry,
+        entry2: &CacheEntry,
+        metric: SsimMetric,
+        score: f32,
+    ) {
+        self.scores.insert(score_key(entry1, entry2, metric), score);
+    }
+
+    /// Drop the entries of files that no longer exist, and the scores of content no file has any
+    /// more.
+    pub fn prune_missing(&mut self) {
+        self.entries.retain(|path, _| path.exists());
+        self.videos.retain(|path, _| path.exists());
+
+        let hashes: HashSet<u64> = self.entries.values().map(|e| e.content_hash).collect();
+        self.scores.retain(|key, _| {
+            key.split(':')
+                .take(2)
+                .all(|hash| u64::from_str_radix(hash, 16).is_ok_and(|h| hashes.contains(&h)))
+        });
+    }
+}
+
+impl FingerprintCache {
+    // The stored entry of a file if it is still valid, otherwise a newly computed one
+    fn lookup(&self, path: &Path) -> Result<CacheEntry, AppError> {
+        let (size, modified) = size_and_modified(path)?;
+
+        match self.entries.get(path) {
+            Some(entry) if entry.size == size && entry.modified == modified => Ok(entry.clone()),
+            // A touched but otherwise unchanged file keeps its entry
+            Some(entry) if entry.size == size && entry.content_hash == file_hash(path)? => {
+                Ok(CacheEntry {
+                    modified,
+                    ..entry.clone()
+                })
+            }
+            _ => {
+                debug!("Caching {}", path.display());
+                compute_entry(path, size, modified)
+            }
+        }
+    }
+
+    // The stored fingerprints of a video if they are still valid, otherwise newly computed ones.
+    // Unlike images, videos aren't hashed to tell a touched file from a changed one
+    fn lookup_video(&self, path: &Path, duration: f64) -> Result<VideoEntry, AppError> {
+        let (size, modified) = size_and_modified(path)?;
+
+        match self.videos.get(path) {
+            Some(entry) if entry.size == size && entry.modified == modified => Ok(entry.clone()),
+            _ => {
+                debug!("Caching {}", path.display());
+                Ok(VideoEntry {
+                    size,
+                    modified,
+                    fingerprints: Video::from_path(path).fingerprints(duration)?,
+                })
+            }
+        }
+    }
+}
+
+// The size of a file and its modification time in nanoseconds since the Unix epoch
+fn size_and_modified(path: &Path) -> Result<(u64, u64), AppError> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+
+    Ok((metadata.len(), modified))
+}
+
+fn compute_entry(path: &Path, size: u64, modified: u64) -> Result<CacheEntry, AppError> {
+    let img = Image::from_path(&path.to_path_buf())?;
+
+    // Decode once and derive everything from the oriented pixels
+    let decoded = img.image()?;
+
+    Ok(CacheEntry {
+        size,
+        modified,
+        content_hash: file_hash(path)?,
+        pixel_hash: hash_pixels(&decoded, HashMode::Full)?,
+        resolution: (decoded.width(), decoded.height()),
+        orientation: img.orientation(),
+        exif: img.exif_summary().ok(),
+        fingerprints: Fingerprints::from_image(&decoded),
+    })
+}
+
+// Scores are symmetric, so order the hashes
+fn score_key(entry1: &CacheEntry, entry2: &CacheEntry, metric: SsimMetric) -> String {
+    let (a, b) = if entry1.content_hash <= entry2.content_hash {
+        (entry1.content_hash, entry2.content_hash)
+    } else {
+        (entry2.content_hash, entry1.content_hash)
+    };
+    format!("{:016x}:{:016x}:{:?}", a, b, metric)
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::PerceptualHash;
+    use crate::progress::NoProgress;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let cache_path = temp.path().join("round-trip.json");
+
+        let image_path = PathBuf::from("test-data/02/face-right-1-small.jpg");
+
+        let mut cache = FingerprintCache::open(&cache_path).unwrap();
+        assert!(cache.is_empty());
+        let entry = cache.get(&image_path).unwrap().clone();
+        cache.insert_score(&entry, &entry, SsimMetric::Global, 1.0);
+        cache.save().unwrap();
+
+        let mut cache = FingerprintCache::open(&cache_path).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&image_path).unwrap(), &entry);
+        assert_eq!(cache.score(&entry, &entry, SsimMetric::Global), Some(1.0));
+        assert_eq!(cache.score(&entry, &entry, SsimMetric::Gaussian), None);
+    }
+
+    #[test]
+    fn test_outdated_cache_is_discarded() {
+        let temp = TempDir::new().unwrap();
+        let cache_path = temp.path().join("outdated.json");
+        fs::write(
+            &cache_path,
+            r#"{"version":0,"entries":{"a.jpg":{}},"scores":{}}"#,
+        )
+        .unwrap();
+
+        let cache = FingerprintCache::open(&cache_path).unwrap();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_changed_file_is_recomputed() {
+        let temp = TempDir::new().unwrap();
+        let image_path = temp.path().join("changed.jpg");
+        fs::copy("test-data/02/face-right-1-small.jpg", &image_path).unwrap();
+
+        let mut cache = FingerprintCache::in_memory();
+        let before = cache.get(&image_path).unwrap().clone();
+        assert_eq!(before.resolution, (250, 375));
+
+        fs::copy("test-data/02/coffee-small.jpg", &image_path).unwrap();
+        let after = cache.get(&image_path).unwrap().clone();
+        assert_ne!(before.content_hash, after.content_hash);
+        assert_ne!(before.resolution, after.resolution);
+    }
+
+    #[test]
+    fn test_prune_missing() {
+        let temp = TempDir::new().unwrap();
+        let deleted = temp.path().join("deleted.jpg");
+        fs::copy("test-data/02/face-right-1-small.jpg", &deleted).unwrap();
+        let kept = PathBuf::from("test-data/02/coffee-small.jpg");
+
+        let mut cache = FingerprintCache::in_memory();
+        let a = cache.get(&deleted).unwrap().clone();
+        let b = cache.get(&kept).unwrap().clone();
+        cache.insert_score(&a, &b, SsimMetric::Global, 0.5);
+        cache.insert_score(&b, &b, SsimMetric::Global, 1.0);
+
+        fs::remove_file(&deleted).unwrap();
+        cache.prune_missing();
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.score(&a, &b, SsimMetric::Global), None);
+        assert_eq!(cache.score(&b, &b, SsimMetric::Global), Some(1.0));
+    }
+
+    #[test]
+    fn test_get_all() {
+        let paths = vec![
+            PathBuf::from("test-data/02/face-right-1-small.jpg"),
+            PathBuf::from("test-data/02/missing.jpg"),
+            PathBuf::from("test-data/02/coffee-small.jpg"),
+        ];
+
+        let mut cache = FingerprintCache::in_memory();
+        let entries = cache.get_all(&paths, &NoProgress, &CancellationToken::new());
+        assert_eq!(entries.len(), 3);
+        assert!(entries[1].is_err());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(entries[0].as_ref().unwrap(), cache.get(&paths[0]).unwrap());
+        assert_eq!(entries[2].as_ref().unwrap(), cache.get(&paths[2]).unwrap());
+    }
+
+    #[test]
+    fn test_video_fingerprints_are_reused() {
+        let temp = TempDir::new().unwrap();
+        let cache_path = temp.path().join("video-cache.json");
+        // Fingerprinting this would fail, so the cached fingerprints have to be returned
+        let video_path = temp.path().join("clip.mp4");
+        fs::write(&video_path, b"not a video").unwrap();
+        let videos = [(video_path.clone(), 10.0)];
+
+        let (size, modified) = size_and_modified(&video_path).unwrap();
+        let frame = Fingerprints {
+            average: PerceptualHash(1),
+            difference: PerceptualHash(2),
+            dct: PerceptualHash(3),
+        };
+        let fingerprints = vec![frame; 5];
+        let mut cache = FingerprintCache::open(&cache_path).unwrap();
+        cache.videos.insert(
+            video_path.clone(),
+            VideoEntry {
+                size,
+                modified,
+                fingerprints: fingerprints.clone(),
+            },
+        );
+        cache.save().unwrap();
+
+        let mut cache = FingerprintCache::open(&cache_path).unwrap();
+        let cached = cache.get_all_videos(&videos, &NoProgress, &CancellationToken::new());
+        assert_eq!(cached[0].as_ref().unwrap(), &fingerprints);
+
+        // A changed video is fingerprinted again
+        fs::write(&video_path, b"still not a video").unwrap();
+        let changed = cache.get_all_videos(&videos, &NoProgress, &CancellationToken::new());
+        assert!(changed[0].is_err());
+    }
+
+    #[test]
+    fn test_score_is_symmetric() {
+        let mut cache = FingerprintCache::in_memory();
+        let entry1 = cache
+            .get(Path::new("test-data/02/face-right-1-small.jpg"))
+            .unwrap()
+            .clone();
+        let entry2 = cache
+            .get(Path::new("test-data/02/coffee-small.jpg"))
+            .unwrap()
+            .clone();
+
+        cache.insert_score(&entry1, &entry2, SsimMetric::Global, 0.5);
+        assert_eq!(cache.score(&entry2, &entry1, SsimMetric::Global), Some(0.5));
+    }
+}
@@ -0,0 +1,40 @@
+//! Cancelling a running scan.
+//! A scan checks its token between files and stops with `AppError::Cancelled` once it is
+//! cancelled. The fingerprints and scores computed until then are saved to the cache, so running
+//! the same scan again resumes where it stopped.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::AppError;
+
+/// Shared between the scan and whoever may stop it. Clones cancel the same scan.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Ask the scan to stop at the next file. Only uses an atomic store, so it can be called from
+    /// a signal handler.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns `AppError::Cancelled` if the scan has been cancelled.
+    pub fn check(&self) -> Result<(), AppError> {
+        if self.is_cancelled() {
+            Err(AppError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
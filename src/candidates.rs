@@ -0,0 +1,215 @@
+//! Candidate pair generation.
+//! Finds the pairs of images that are plausibly duplicates so that the expensive SSIM comparison
+//! only runs on those, rather than on every pair.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::fingerprint::PerceptualHash;
+
+/// The cheap-to-compute features of an image used to find candidates.
+#[derive(Debug, Clone, Copy)]
+pub struct CandidateKey {
+    pub hash: PerceptualHash,
+    pub aspect_ratio: f32,
+}
+
+/// A BK-tree indexing perceptual hashes by Hamming distance.
+/// Lookups prune every subtree that the triangle inequality rules out.
+#[derive(Debug, Default)]
+pub struct BkTree {
+    root: Option<Node>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node {
+    hash: PerceptualHash,
+    // Items that share this exact hash
+    ids: Vec<usize>,
+    children: HashMap<u32, Node>,
+}
+
+impl BkTree {
+    pub fn new() -> BkTree {
+        BkTree::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert an item identified by `id` with the given hash.
+    pub fn insert(&mut self, hash: PerceptualHash, id: usize) {
+        self.len += 1;
+
+        let mut node = match &mut self.root {
+            Some(node) => node,
+            None => {
+                self.root = Some(Node::new(hash, id));
+                return;
+            }
+        };
+
+        loop {
+            let distance = node.hash.distance(&hash);
+            if distance == 0 {
+                node.ids.push(id);
+                return;
+            }
+            match node.children.entry(distance) {
+                Entry::Occupied(entry) => node = entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(Node::new(hash, id));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns the ids of all items within `max_distance` of `hash`.
+    pub fn find(&self, hash: PerceptualHash, max_distance: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack: Vec<&Node> = self.root.iter().collect();
+
+        while let Some(node) = stack.pop() {
+            let distance = node.hash.distance(&hash);
+            if distance <= max_distance {
+                found.extend_from_slice(&node.ids);
+            }
+
+            let min = distance.saturating_sub(max_distance);
+            let max = distance + max_distance;
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| (min..=max).contains(*d))
+                    .map(|(_, child)| child),
+            );
+        }
+
+        found
+    }
+}
+
+impl Node {
+    fn new(hash: PerceptualHash, id: usize) -> Node {
+        Node {
+            hash,
+            ids: vec![id],
+            children: HashMap::new(),
+        }
+    }
+}
+
+/// Returns the pairs `(i, j)`, `i < j`, of keys whose aspect ratios differ by at most
+/// `aspect_ratio_tolerance` and whose hashes are within `max_distance` bits of each other.
+/// Pairs are sorted so that the comparison order is reproducible.
+pub fn candidate_pairs(
+    keys: &[CandidateKey],
+    max_distance: u32,
+    aspect_ratio_tolerance: f32,
+) -> Vec<(usize, usize)> {
+    // Bucket by aspect ratio so that each image is only looked up among images of a similar
+    // shape. Buckets are as wide as the tolerance, so neighbouring buckets must be searched too.
+    let bucket_of = |aspect_ratio: f32| (aspect_ratio / aspect_ratio_tolerance).floor() as i64;
+
+    let mut buckets: HashMap<i64, BkTree> = HashMap::new();
+    for (id, key) in keys.iter().enumerate() {
+        buckets
+            .entry(bucket_of(key.aspect_ratio))
+            .or_default()
+            .insert(key.hash, id);
+    }
+
+    let mut pairs = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        let bucket = bucket_of(key.aspect_ratio);
+        for neighbour in bucket - 1..=bucket + 1 {
+            let Some(tree) = buckets.get(&neighbour) else {
+                continue;
+            };
+            for j in tree.find(key.hash, max_distance) {
+                if j > i
+                    && (keys[j].aspect_ratio - key.aspect_ratio).abs() <= aspect_ratio_tolerance
+                {
+                    pairs.push((i, j));
+                }
+            }
+        }
+    }
+
+    pairs.sort_unstable();
+    pairs
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bktree_find() {
+        let mut tree = BkTree::new();
+        tree.insert(PerceptualHash(0b0000), 0);
+        tree.insert(PerceptualHash(0b0001), 1);
+        tree.insert(PerceptualHash(0b0011), 2);
+        tree.insert(PerceptualHash(0b1111), 3);
+        tree.insert(PerceptualHash(0b0000), 4);
+
+        assert_eq!(tree.len(), 5);
+
+        let mut found = tree.find(PerceptualHash(0b0000), 1);
+        found.sort();
+        assert_eq!(found, vec![0, 1, 4]);
+
+        let mut found = tree.find(PerceptualHash(0b0111), 1);
+        found.sort();
+        assert_eq!(found, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_bktree_matches_brute_force() {
+        // Deterministic pseudo-random hashes
+        let hashes: Vec<PerceptualHash> = (0..500u64)
+            .map(|i| PerceptualHash(i.wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(17)))
+            .collect();
+
+        let mut tree = BkTree::new();
+        for (id, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, id);
+        }
+
+        for query in hashes.iter().take(20) {
+            let mut found = tree.find(*query, 24);
+            found.sort();
+            let expected: Vec<usize> = (0..hashes.len())
+                .filter(|&i| hashes[i].distance(query) <= 24)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_candidate_pairs() {
+        let key = |hash, aspect_ratio| CandidateKey {
+            hash: PerceptualHash(hash),
+            aspect_ratio,
+        };
+        let keys = vec![
+            key(0b0000, 1.333),
+            key(0b0001, 1.335),
+            // Same hash, different shape
+            key(0b0000, 0.75),
+            // Same shape, different hash
+            key(0xffff, 1.333),
+        ];
+
+        assert_eq!(candidate_pairs(&keys, 2, 0.01), vec![(0, 1)]);
+    }
+}
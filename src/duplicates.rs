@@ -1,77 +1,648 @@
-//! Duplicate image detection.
+//! Duplicate image and video detection.
 
-use image::DynamicImage;
-use log::debug;
-use std::collections::HashMap;
+use log::{debug, warn};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use twox_hash::XxHash64;
 
+use crate::cache::{CacheEntry, FingerprintCache};
+use crate::cancel::CancellationToken;
+use crate::candidates::{candidate_pairs, CandidateKey};
+use crate::error::AppError;
+use crate::exact::find_exact_duplicates;
+use crate::fingerprint::{HashAlgorithm, PerceptualHash};
 use crate::image::Image;
-use crate::similarity::ssim_index2;
+use crate::indexer::find_raw_pairs;
+use crate::keeper::KeeperPolicy;
+use crate::motion::{find_live_photos, is_motion_photo};
+use crate::progress::{NoProgress, Phase, Progress};
+use crate::raw;
+use crate::report::{root_of, DuplicateGroup, DuplicateReport, ReportedFile};
+use crate::similarity::{compute_ssim, SsimMetric};
+use crate::thumbnail::ThumbnailCache;
+use crate::video::{self, fingerprint_distance, Video, VideoMetadata};
 
-pub fn create_similarity_index(image_paths: Vec<PathBuf>) -> HashMap<String, Vec<String>> {
+/// Videos whose durations differ by less than this fraction, or by less than
+/// `MIN_DURATION_TOLERANCE` seconds, may be duplicates.
+const DURATION_TOLERANCE: f64 = 0.02;
+const MIN_DURATION_TOLERANCE: f64 = 0.5;
+
+/// Number of images or pairs processed between checks for cancellation and checkpoints.
+const CHECKPOINT_BATCH: usize = 256;
+
+/// Settings for duplicate detection.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Minimum similarity score for two images to count as duplicates.
+    pub threshold: f32,
+    /// Perceptual hash used to find candidate pairs.
+    pub hash_algorithm: HashAlgorithm,
+    /// Maximum Hamming distance between the hashes of a candidate pair.
+    pub max_hash_distance: u32,
+    /// Maximum difference between the aspect ratios of a candidate pair.
+    pub aspect_ratio_tolerance: f32,
+    /// How candidate pairs are scored.
+    pub metric: SsimMetric,
+    /// Memory for the thumbnails of images being compared, in bytes.
+    pub thumbnail_cache_size: usize,
+    /// How the file to keep is chosen from each group.
+    pub keeper_policy: KeeperPolicy,
+    /// The scanned folders. Each file is tagged with the deepest one containing it before the
+    /// keeper of its group is chosen.
+    pub roots: Vec<PathBuf>,
+    /// Receives the progress of each phase.
+    pub progress: Arc<dyn Progress>,
+    /// Stops the scan with `AppError::Cancelled` when cancelled.
+    pub cancel: CancellationToken,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            threshold: 0.95,
+            hash_algorithm: HashAlgorithm::Dct,
+            max_hash_distance: 10,
+            aspect_ratio_tolerance: 0.01,
+            metric: SsimMetric::Global,
+            thumbnail_cache_size: 256 * 1024 * 1024,
+            keeper_policy: KeeperPolicy::default(),
+            roots: Vec::new(),
+            progress: Arc::new(NoProgress),
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// Group images whose similarity score exceeds `options.threshold`.
+///
+/// Fingerprints and similarity scores are read from and added to `cache`, which is saved
+/// periodically and when the scan is cancelled. Running the scan again with the same cache then
+/// resumes after the last file fingerprinted and the last pair compared.
+pub fn create_similarity_index(
+    image_paths: Vec<PathBuf>,
+    options: &ScanOptions,
+    cache: &mut FingerprintCache,
+) -> Result<HashMap<String, Vec<String>>, AppError> {
     // Key is the hash of the image path and the value is a vector of similar image paths
     let mut similarity_index: HashMap<String, Vec<String>> = HashMap::new();
 
-    // Similarity scores of image pairs that have already been compared
-    let mut comparison_cache: HashMap<(String, String), f32> = HashMap::new();
+    for group in group_images(&image_paths, HashMap::new(), options, cache)? {
+        similarity_index.insert(
+            calculate_hash(&group[0].path),
+            group
+                .iter()
+                .map(|member| member.path.to_string_lossy().into_owned())
+                .collect(),
+        );
+    }
 
-    // Avoid repeated disk I/O for the same image
-    let mut image_cache: HashMap<String, DynamicImage> = HashMap::new();
+    Ok(similarity_index)
+}
 
-    debug!("Processing {} images", image_paths.len());
+/// Find duplicate images and describe them in a report.
+///
+/// Fingerprints and similarity scores are read from and added to `cache`, as with
+/// `create_similarity_index`.
+pub fn find_duplicates(
+    image_paths: &[PathBuf],
+    options: &ScanOptions,
+    cache: &mut FingerprintCache,
+) -> Result<DuplicateReport, AppError> {
+    let groups = group_images(image_paths, HashMap::new(), options, cache)?;
+    Ok(DuplicateReport::new(reported_groups(groups, options)))
+}
+
+/// Find duplicate images and videos and describe them in one report.
+///
+/// The video of a Live Photo follows its still as a companion rather than being compared with
+/// the other videos, so the two are kept or removed together.
+pub fn find_media_duplicates(
+    image_paths: &[PathBuf],
+    video_paths: &[PathBuf],
+    options: &ScanOptions,
+    cache: &mut FingerprintCache,
+) -> Result<DuplicateReport, AppError> {
+    let mut companions: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for live_photo in find_live_photos(image_paths, video_paths) {
+        debug!(
+            "Live Photo: {} {}",
+            live_photo.still.display(),
+            live_photo.video.display()
+        );
+        companions
+            .entry(live_photo.still)
+            .or_default()
+            .push(live_photo.video);
+    }
+    let paired: HashSet<&PathBuf> = companions.values().flatten().collect();
+    let video_paths: Vec<PathBuf> = video_paths
+        .iter()
+        .filter(|path| !paired.contains(path))
+        .cloned()
+        .collect();
+
+    let groups = group_images(image_paths, companions, options, cache)?;
+    let mut report = DuplicateReport::new(reported_groups(groups, options));
+    report.extend(find_video_duplicates(&video_paths, options, cache)?);
+
+    Ok(report)
+}
+
+/// Find duplicate videos and describe them in groups like those of `find_duplicates`.
+///
+/// Identical files are always found. Re-encoded copies are found when their durations and aspect
+/// ratios match and the mean distance between their frame fingerprints is within
+/// `options.max_hash_distance`, which needs `ffmpeg` to decode the frames. Frame fingerprints are
+/// read from and added to `cache`, as with `create_similarity_index`.
+pub fn find_video_duplicates(
+    video_paths: &[PathBuf],
+    options: &ScanOptions,
+    cache: &mut FingerprintCache,
+) -> Result<Vec<DuplicateGroup>, AppError> {
+    debug!("Processing {} videos", video_paths.len());
+
+    let mut exact_copies: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for group in find_exact_duplicates(video_paths) {
+        debug!("Found {} identical files: {:?}", group.len(), group);
+        if let Some((first, copies)) = group.split_first() {
+            exact_copies.insert(first.clone(), copies.to_vec());
+        }
+    }
+    let copies: HashSet<&PathBuf> = exact_copies.values().flatten().collect();
 
-    for (i, path1) in image_paths.iter().enumerate() {
-        debug!("Processing image: {}", path1.display());
+    let candidates: Vec<&PathBuf> = video_paths
+        .iter()
+        .filter(|path| !copies.contains(path))
+        .collect();
+    let read: Vec<_> = candidates
+        .par_iter()
+        .map(|path| Video::from_path(path).metadata())
+        .collect();
 
-        // Skip if the image has already been added to the similarity index
-        if contains_value(&similarity_index, &path1.to_string_lossy()) {
-            debug!("Skipping image: {}", path1.display());
-            continue;
+    // Videos that can't be read are still grouped with their identical copies
+    let paths: Vec<PathBuf> = candidates.into_iter().cloned().collect();
+    let metadata: Vec<Option<VideoMetadata>> = paths
+        .iter()
+        .zip(read)
+        .map(|(path, m)| {
+            m.map_err(|e| warn!("Can't read video {}: {}", path.display(), e))
+                .ok()
+        })
+        .collect();
+
+    let mut fingerprints: Vec<Option<Vec<_>>> = vec![None; paths.len()];
+    if paths.len() > 1 && video::ffmpeg_available() {
+        let (indices, videos): (Vec<usize>, Vec<(PathBuf, f64)>) = paths
+            .iter()
+            .zip(&metadata)
+            .enumerate()
+            .filter_map(|(i, (path, m))| Some((i, (path.clone(), m.as_ref()?.duration))))
+            .unzip();
+        debug!("Fingerprinting {} videos", videos.len());
+
+        let progress = options.progress.as_ref();
+        progress.start(Phase::Videos, Some(videos.len() as u64));
+        let mut fingerprinted = Vec::with_capacity(videos.len());
+        for batch in videos.chunks(CHECKPOINT_BATCH) {
+            fingerprinted.extend(cache.get_all_videos(batch, progress, &options.cancel));
+            checkpoint(cache, &options.cancel)?;
         }
+        progress.finish(Phase::Videos);
 
-        // Add the image path to the cache
-        let hash1 = calculate_hash(path1);
-        similarity_index
-            .entry(hash1.clone())
-            .or_insert_with(Vec::new)
-            .push(path1.to_string_lossy().into_owned());
+        for (i, frames) in indices.into_iter().zip(fingerprinted) {
+            match frames {
+                Ok(frames) => {
+                    fingerprints[i] = Some(
+                        frames
+                            .iter()
+                            .map(|frame| frame.get(options.hash_algorithm))
+                            .collect(),
+                    )
+                }
+                Err(e) => warn!("Can't fingerprint video {}: {}", paths[i].display(), e),
+            }
+        }
+    } else if paths.len() > 1 {
+        warn!("ffmpeg not found, only identical videos will be detected");
+    }
+    options.cancel.check()?;
 
-        // Compare similarity with all other images
-        for path2 in image_paths.iter().skip(i + 1) {
-            debug!("Comparing image: {}", path2.display());
+    Ok(group_videos(
+        paths,
+        metadata,
+        &fingerprints,
+        exact_copies,
+        options,
+    ))
+}
 
-            let similarity =
-                get_or_calculate_similarity(&mut comparison_cache, &mut image_cache, path1, path2);
+/// Group the videos whose durations and aspect ratios match and whose frame fingerprints are
+/// within `options.max_hash_distance`, each with its identical copies. Videos without
+/// fingerprints or metadata are only grouped with their copies.
+fn group_videos(
+    paths: Vec<PathBuf>,
+    metadata: Vec<Option<VideoMetadata>>,
+    fingerprints: &[Option<Vec<PerceptualHash>>],
+    mut exact_copies: HashMap<PathBuf, Vec<PathBuf>>,
+    options: &ScanOptions,
+) -> Vec<DuplicateGroup> {
+    let mut parents: Vec<usize> = (0..paths.len()).collect();
+    let mut scores: Vec<f32> = paths
+        .iter()
+        .map(|path| {
+            if exact_copies.contains_key(path) {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    for i in 0..paths.len() {
+        for j in i + 1..paths.len() {
+            let (Some(a), Some(b)) = (&metadata[i], &metadata[j]) else {
+                continue;
+            };
+            let tolerance =
+                (a.duration.max(b.duration) * DURATION_TOLERANCE).max(MIN_DURATION_TOLERANCE);
+            if (a.duration - b.duration).abs() > tolerance
+                || (aspect_ratio(a.resolution) - aspect_ratio(b.resolution)).abs()
+                    > options.aspect_ratio_tolerance
+            {
+                continue;
+            }
 
-            if similarity > 0.95 {
-                similarity_index
-                    .entry(hash1.clone())
-                    .or_insert_with(Vec::new)
-                    .push(path2.to_string_lossy().into_owned());
+            let (Some(hashes1), Some(hashes2)) = (&fingerprints[i], &fingerprints[j]) else {
+                continue;
+            };
+            let Some(distance) = fingerprint_distance(hashes1, hashes2) else {
+                continue;
+            };
+            debug!(
+                "Video distance {}: {} {}",
+                distance,
+                paths[i].display(),
+                paths[j].display()
+            );
 
-                debug!(
-                    "Found similar: similarity_index now {:#?}",
-                    similarity_index
-                );
+            if distance <= options.max_hash_distance as f32 {
+                let (root1, root2) = (find_root(&mut parents, i), find_root(&mut parents, j));
+                parents[root1.max(root2)] = root1.min(root2);
+                let similarity = 1.0 - distance / 64.0;
+                scores[i] = scores[i].max(similarity);
+                scores[j] = scores[j].max(similarity);
             }
         }
     }
 
-    // Discard entries with no duplicates
-    similarity_index.retain(|_, vec| vec.len() > 1);
+    let mut groups: Vec<Vec<ReportedFile>> = Vec::new();
+    let mut group_of_root: HashMap<usize, usize> = HashMap::new();
+    for (i, (path, m)) in paths.into_iter().zip(metadata).enumerate() {
+        let root = find_root(&mut parents, i);
+        let group = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
 
-    similarity_index
+        let copies = exact_copies.remove(&path).unwrap_or_default();
+        for (path, score) in
+            iter::once((path, scores[i])).chain(copies.into_iter().map(|c| (c, 1.0)))
+        {
+            groups[group].push(ReportedFile {
+                size: fs::metadata(&path).map_or(0, |m| m.len()),
+                sidecar: path.with_extension("xmp").exists(),
+                root: root_of(&path, &options.roots),
+                path,
+                resolution: m.as_ref().map(|m| m.resolution),
+                score: Some(score),
+                date_time_original: m.as_ref().and_then(|m| m.creation_date.clone()),
+                companions: Vec::new(),
+                motion: false,
+            });
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|group| DuplicateGroup::with_policy(group, &options.keeper_policy))
+        .collect()
+}
+
+fn aspect_ratio((width, height): (u32, u32)) -> f32 {
+    width as f32 / height as f32
+}
+
+fn reported_groups(groups: Vec<Vec<GroupMember>>, options: &ScanOptions) -> Vec<DuplicateGroup> {
+    // Checking for sidecars and embedded videos reads every file, so do it in parallel
+    groups
+        .into_par_iter()
+        .map(|group| {
+            DuplicateGroup::with_policy(
+                group
+                    .into_iter()
+                    .map(|member| ReportedFile {
+                        sidecar: Image::from_path(&member.path).is_ok_and(|img| img.has_sidecar()),
+                        motion: is_motion_photo(&member.path),
+                        root: root_of(&member.path, &options.roots),
+                        size: member.entry.as_ref().map_or_else(
+                            || fs::metadata(&member.path).map_or(0, |m| m.len()),
+                            |entry| entry.size,
+                        ),
+                        path: member.path,
+                        resolution: member.entry.as_ref().map(|entry| entry.resolution),
+                        score: Some(member.score),
+                        date_time_original: member
+                            .entry
+                            .and_then(|entry| entry.exif)
+                            .and_then(|exif| exif.date_time_original),
+                        companions: member.companions,
+                    })
+                    .collect(),
+                &options.keeper_policy,
+            )
+        })
+        .collect()
 }
 
-fn contains_value(map: &HashMap<String, Vec<String>>, value: &str) -> bool {
-    for vec in map.values() {
-        if vec.contains(&value.to_string()) {
-            return true;
+struct GroupMember {
+    path: PathBuf,
+    // None for identical files that can't be decoded
+    entry: Option<CacheEntry>,
+    // Highest similarity to another member of the group
+    score: f32,
+    // RAW files and Live Photo videos paired with this image
+    companions: Vec<PathBuf>,
+}
+
+// Groups of duplicates, each with at least two members, in the order of their first member.
+//
+// A RAW file with a rendered image of the same shot is not compared at all, but follows the image
+// as its companion, so the two are never reported as duplicates of each other. If the image can't
+// be decoded the RAW file is compared on its own. `companions` holds other such files found by the
+// caller. Bit-identical files are grouped first without decoding them, and only one file of each
+// such group goes on to be fingerprinted. The group is reported even if that file can't be decoded.
+// Images whose decoded pixels are identical are then grouped without being compared. Only pairs
+// with similar perceptual hashes and aspect ratios are compared, so the cost grows with the number
+// of plausible duplicates rather than with the square of the number of images. Fingerprints and
+// comparisons are computed in parallel, but their results are applied in the order of the paths so
+// that every run gives the same groups.
+fn group_images(
+    image_paths: &[PathBuf],
+    mut companions: HashMap<PathBuf, Vec<PathBuf>>,
+    options: &ScanOptions,
+    cache: &mut FingerprintCache,
+) -> Result<Vec<Vec<GroupMember>>, AppError> {
+    // Avoid decoding the same image again for each of its pairs
+    let thumbnails = ThumbnailCache::new(options.thumbnail_cache_size);
+
+    debug!("Processing {} images", image_paths.len());
+    let order: HashMap<&PathBuf, usize> = image_paths.iter().zip(0..).collect();
+
+    for pair in find_raw_pairs(image_paths) {
+        debug!(
+            "RAW pair: {} {}",
+            pair.rendered.display(),
+            pair.raw.display()
+        );
+        companions.entry(pair.rendered).or_default().push(pair.raw);
+    }
+    let paired: HashSet<&PathBuf> = companions.values().flatten().collect();
+    let image_paths: Vec<PathBuf> = image_paths
+        .iter()
+        .filter(|path| !paired.contains(path))
+        .cloned()
+        .collect();
+
+    // Files identical to an earlier file are represented by it from here on
+    let mut exact_copies: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for group in find_exact_duplicates(&image_paths) {
+        debug!("Found {} identical files: {:?}", group.len(), group);
+        if let Some((first, copies)) = group.split_first() {
+            exact_copies.insert(first.clone(), copies.to_vec());
+        }
+    }
+    let copies: HashSet<&PathBuf> = exact_copies.values().flatten().collect();
+
+    // Images that can't be read are left out of the comparison
+    let candidates: Vec<PathBuf> = image_paths
+        .iter()
+        .filter(|path| !copies.contains(path))
+        .cloned()
+        .collect();
+    debug!("Fingerprinting {} images", candidates.len());
+
+    let mut paths = Vec::with_capacity(candidates.len());
+    let mut entries = Vec::with_capacity(candidates.len());
+    let progress = options.progress.as_ref();
+    progress.start(Phase::Fingerprinting, Some(candidates.len() as u64));
+    let mut fingerprinted = Vec::with_capacity(candidates.len());
+    for batch in candidates.chunks(CHECKPOINT_BATCH) {
+        fingerprinted.extend(cache.get_all(batch, progress, &options.cancel));
+        checkpoint(cache, &options.cancel)?;
+    }
+    progress.finish(Phase::Fingerprinting);
+    // Identical files are duplicates even if they can't be decoded
+    let mut groups: Vec<Vec<GroupMember>> = Vec::new();
+    let mut unpaired: Vec<PathBuf> = Vec::new();
+    for (path, entry) in candidates.iter().zip(fingerprinted) {
+        match entry {
+            Ok(entry) => {
+                entries.push(entry);
+                paths.push(path.clone());
+            }
+            Err(e) => {
+                warn!("Skipping image {}: {}", path.display(), e);
+                // RAW files paired with the image are compared on their own instead
+                let copies = exact_copies.get(path).into_iter().flatten();
+                for rendered in iter::once(path).chain(copies) {
+                    if let Some(paired) = companions.get_mut(rendered) {
+                        let (raws, others) = paired.drain(..).partition(|path| raw::is_raw(path));
+                        *paired = others;
+                        unpaired.extend(raws);
+                    }
+                }
+                if let Some(copies) = exact_copies.remove(path) {
+                    groups.push(
+                        iter::once(path.clone())
+                            .chain(copies)
+                            .map(|path| GroupMember {
+                                companions: companions.remove(&path).unwrap_or_default(),
+                                path,
+                                entry: None,
+                                score: 1.0,
+                            })
+                            .collect(),
+                    );
+                }
+            }
+        }
+    }
+    if !unpaired.is_empty() {
+        debug!("Fingerprinting {} unpaired RAW files", unpaired.len());
+        for (path, entry) in
+            unpaired
+                .iter()
+                .zip(cache.get_all(&unpaired, &NoProgress, &options.cancel))
+        {
+            match entry {
+                Ok(entry) => {
+                    entries.push(entry);
+                    paths.push(path.clone());
+                }
+                Err(e) => warn!("Skipping image {}: {}", path.display(), e),
+            }
+        }
+        checkpoint(cache, &options.cancel)?;
+    }
+
+    let keys: Vec<CandidateKey> = entries
+        .iter()
+        .map(|entry| CandidateKey {
+            hash: entry.fingerprints.get(options.hash_algorithm),
+            aspect_ratio: entry.aspect_ratio(),
+        })
+        .collect();
+
+    let pairs = candidate_pairs(
+        &keys,
+        options.max_hash_distance,
+        options.aspect_ratio_tolerance,
+    );
+    debug!("Comparing {} candidate pairs", pairs.len());
+
+    // Disjoint sets of similar images, each rooted at its first member
+    let mut parents: Vec<usize> = (0..paths.len()).collect();
+
+    // Identical files are perfect matches
+    let mut scores: Vec<f32> = paths
+        .iter()
+        .map(|path| {
+            if exact_copies.contains_key(path) {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    // Images with identical pixels are duplicates whatever their metadata, so need no SSIM
+    let mut first_with_pixels: HashMap<u64, usize> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let first = *first_with_pixels.entry(entry.pixel_hash).or_insert(i);
+        if first != i {
+            debug!(
+                "Identical pixels: {} {}",
+                paths[first].display(),
+                paths[i].display()
+            );
+            parents[i] = first;
+            scores[i] = 1.0;
+            scores[first] = 1.0;
+        }
+    }
+
+    // Skip images that have already been grouped together
+    let pairs: Vec<(usize, usize)> = pairs
+        .into_iter()
+        .filter(|&(i, j)| find_root(&mut parents, i) != find_root(&mut parents, j))
+        .collect();
+
+    // Compare the images in parallel, then join them in the order of the pairs so that the groups
+    // don't depend on which comparison finishes first
+    progress.start(Phase::Comparing, Some(pairs.len() as u64));
+    for batch in pairs.chunks(CHECKPOINT_BATCH) {
+        let calculated: Vec<Option<f32>> = batch
+            .par_iter()
+            .map(|&(i, j)| {
+                if options.cancel.is_cancelled() {
+                    return None;
+                }
+                let cached = cache.score(&entries[i], &entries[j], options.metric);
+                let similarity = match cached {
+                    Some(_) => None,
+                    None => calculate_similarity(&thumbnails, &paths[i], &paths[j], options.metric),
+                };
+                progress.advance(Phase::Comparing, 1);
+                similarity
+            })
+            .collect();
+
+        for (&(i, j), calculated) in batch.iter().zip(calculated) {
+            let (entry1, entry2) = (&entries[i], &entries[j]);
+            let similarity = match (cache.score(entry1, entry2, options.metric), calculated) {
+                (Some(score), _) => score,
+                (None, Some(score)) => {
+                    cache.insert_score(entry1, entry2, options.metric, score);
+                    score
+                }
+                (None, None) => 0.0,
+            };
+
+            if similarity > options.threshold {
+                let (root1, root2) = (find_root(&mut parents, i), find_root(&mut parents, j));
+                parents[root1.max(root2)] = root1.min(root2);
+                scores[i] = scores[i].max(similarity);
+                scores[j] = scores[j].max(similarity);
+            }
         }
+        checkpoint(cache, &options.cancel)?;
     }
-    false
+    progress.finish(Phase::Comparing);
+
+    let mut group_of_root: HashMap<usize, usize> = HashMap::new();
+    for (i, (path, entry)) in paths.into_iter().zip(entries).enumerate() {
+        let root = find_root(&mut parents, i);
+        let group = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+
+        let copies = exact_copies.remove(&path).unwrap_or_default();
+        groups[group].push(GroupMember {
+            companions: companions.remove(&path).unwrap_or_default(),
+            path,
+            entry: Some(entry.clone()),
+            score: scores[i],
+        });
+        for copy in copies {
+            groups[group].push(GroupMember {
+                companions: companions.remove(&copy).unwrap_or_default(),
+                path: copy,
+                entry: Some(entry.clone()),
+                score: 1.0,
+            });
+        }
+    }
+
+    // Discard groups with no duplicates, and put those of undecodable files in place
+    groups.retain(|group| group.len() > 1);
+    groups.sort_by_key(|group| order[&group[0].path]);
+
+    Ok(groups)
+}
+
+// Save the cache if it is due, or stop with everything done so far saved if the scan is cancelled
+fn checkpoint(cache: &mut FingerprintCache, cancel: &CancellationToken) -> Result<(), AppError> {
+    if cancel.is_cancelled() {
+        cache.save()?;
+        return Err(AppError::Cancelled);
+    }
+
+    cache.checkpoint()
+}
+
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
 }
 
 fn calculate_hash(path: &PathBuf) -> String {
@@ -80,38 +651,31 @@ fn calculate_hash(path: &PathBuf) -> String {
     hasher.finish().to_string()
 }
 
-fn get_or_calculate_similarity(
-    cache: &mut HashMap<(String, String), f32>,
-    image_cache: &mut HashMap<String, DynamicImage>,
-    path1: &PathBuf,
-    path2: &PathBuf,
-) -> f32 {
-    let key = if path1 < path2 {
-        (
-            path1.to_string_lossy().into_owned(),
-            path2.to_string_lossy().into_owned(),
-        )
-    } else {
-        (
-            path2.to_string_lossy().into_owned(),
-            path1.to_string_lossy().into_owned(),
-        )
-    };
-
-    *cache.entry(key).or_insert_with(|| {
-        let img1 = get_or_load_image(image_cache, path1);
-        let img2 = get_or_load_image(image_cache, path2);
-
-        ssim_index2(&img1, &img2).unwrap()
-    })
-}
+// The similarity of the thumbnails of two images, or None if they can't be compared
+fn calculate_similarity(
+    thumbnails: &ThumbnailCache,
+    path1: &Path,
+    path2: &Path,
+    metric: SsimMetric,
+) -> Option<f32> {
+    debug!("Comparing images: {} {}", path1.display(), path2.display());
 
-fn get_or_load_image(cache: &mut HashMap<String, DynamicImage>, path: &PathBuf) -> DynamicImage {
-    let img = Image::from_path(path).unwrap();
-    cache
-        .entry(path.to_string_lossy().into_owned())
-        .or_insert_with(|| img.image().expect("Failed to open image"))
-        .clone()
+    let result = thumbnails.get(path1).and_then(|img1| {
+        let img2 = thumbnails.get(path2)?;
+        compute_ssim(&img1, &img2, metric, false)
+    });
+    match result {
+        Ok(result) => Some(result.score as f32),
+        Err(e) => {
+            warn!(
+                "Failed to compare {} and {}: {}",
+                path1.display(),
+                path2.display(),
+                e
+            );
+            None
+        }
+    }
 }
 
 // tests ------------------------------------------------------
@@ -120,8 +684,13 @@ fn get_or_load_image(cache: &mut HashMap<String, DynamicImage>, path: &PathBuf)
 mod tests {
 
     use super::*;
-    use crate::indexer::index_images_in_folder;
-    use std::{path::PathBuf, time::Instant};
+    use crate::indexer::{
+        index_images_in_folder, index_media, index_videos_in_folder, IndexerOptions,
+    };
+    use crate::progress::ProgressFn;
+    use crate::video::tests::encode;
+    use std::{fs, path::PathBuf, time::Instant};
+    use tempfile::TempDir;
 
     #[test]
     fn test_duplicates() {
@@ -129,12 +698,397 @@ mod tests {
 
         let start = Instant::now();
 
-        let similarity_index = create_similarity_index(image_paths);
+        let similarity_index = create_similarity_index(
+            image_paths,
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
 
         let duration = start.elapsed();
         println!("->> Time elapsed is: {:?}", duration);
 
         println!("{:#?}", similarity_index);
+
+        // coffee, face-right, house and the orientation fixtures
+        assert_eq!(similarity_index.len(), 4);
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let image_paths = get_test_images();
+
+        let report = find_duplicates(
+            &image_paths,
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+        assert_eq!(report.groups.len(), 4);
+
+        // The same picture stored with each orientation
+        let orientation = report
+            .groups
+            .iter()
+            .find(|group| group.keeper.starts_with("test-data/orientation"))
+            .unwrap();
+        assert_eq!(orientation.files.len(), 8);
+
+        let house = report
+            .groups
+            .iter()
+            .find(|group| {
+                group.keeper.ends_with("house.jpg") || group.keeper.ends_with("house-duplicate.jpg")
+            })
+            .unwrap();
+        assert_eq!(house.files.len(), 2);
+        for file in &house.files {
+            assert_eq!(file.resolution, Some((4032, 3024)));
+            assert_eq!(file.score, Some(1.0));
+        }
+
+        // Copies of the same file are listed with the file they were found with
+        let coffee = report
+            .groups
+            .iter()
+            .find(|group| {
+                group
+                    .files
+                    .iter()
+                    .any(|f| f.path.ends_with("coffee-small.jpg"))
+            })
+            .unwrap();
+        assert_eq!(coffee.files.len(), 3);
+        assert!(coffee.keeper.ends_with("coffee.jpeg"));
+    }
+
+    #[test]
+    fn test_parallel_scan_is_deterministic() {
+        let image_paths = index_images_in_folder(PathBuf::from("test-data/02"));
+        let scan = || {
+            find_duplicates(
+                &image_paths,
+                &ScanOptions::default(),
+                &mut FingerprintCache::in_memory(),
+            )
+            .unwrap()
+        };
+
+        let single_threaded = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(scan);
+        assert!(!single_threaded.groups.is_empty());
+        assert_eq!(scan(), single_threaded);
+    }
+
+    #[test]
+    fn test_cancelled_scan_resumes() {
+        let temp = TempDir::new().unwrap();
+        let cache_path = temp.path().join("resume-cache.json");
+        let image_paths = index_images_in_folder(PathBuf::from("test-data/02"));
+
+        // Cancel once two images have been fingerprinted
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let options = ScanOptions {
+            progress: Arc::new(ProgressFn::new(move |phase, done, _| {
+                if phase == Phase::Fingerprinting && done == 2 {
+                    token.cancel();
+                }
+            })),
+            cancel,
+            ..ScanOptions::default()
+        };
+        let mut cache = FingerprintCache::open(&cache_path).unwrap();
+        let result = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| create_similarity_index(image_paths.clone(), &options, &mut cache));
+        assert!(matches!(result, Err(AppError::Cancelled)));
+
+        // The fingerprints computed before cancelling were saved
+        let mut cache = FingerprintCache::open(&cache_path).unwrap();
+        assert_eq!(cache.len(), 2);
+
+        let resumed =
+            create_similarity_index(image_paths.clone(), &ScanOptions::default(), &mut cache)
+                .unwrap();
+        let fresh = create_similarity_index(
+            image_paths,
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+        assert_eq!(resumed, fresh);
+    }
+
+    #[test]
+    fn test_raw_pair_is_one_asset() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        for folder in ["a", "b"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        // Byte-identical to the JPEGs, so reported as a copy if it weren't paired
+        let fixture = PathBuf::from("test-data/orientation/orientation-1.jpg");
+        for path in ["a/shot.jpg", "a/shot.dng", "b/shot.jpg"] {
+            fs::copy(&fixture, dir.join(path)).unwrap();
+        }
+
+        let report = find_duplicates(
+            &index_images_in_folder(dir.to_path_buf()),
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+
+        assert_eq!(report.groups.len(), 1);
+        let group = &report.groups[0];
+        assert_eq!(group.files.len(), 2);
+        assert_eq!(group.keeper, dir.join("a/shot.jpg"));
+        assert_eq!(group.files[0].companions, vec![dir.join("a/shot.dng")]);
+        assert!(group.files[1].companions.is_empty());
+    }
+
+    #[test]
+    fn test_raw_of_undecodable_image_stands_alone() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        for folder in ["a", "b"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        fs::write(dir.join("a/shot.jpg"), b"not an image").unwrap();
+        crate::raw::tests::write_raw(&dir.join("a/shot.dng"));
+        fs::copy(dir.join("a/shot.dng"), dir.join("b/shot.dng")).unwrap();
+
+        let report = find_duplicates(
+            &index_images_in_folder(dir.to_path_buf()),
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+
+        assert_eq!(report.groups.len(), 1);
+        let mut files: Vec<_> = report.groups[0].files.iter().map(|f| &f.path).collect();
+        files.sort();
+        assert_eq!(files, [&dir.join("a/shot.dng"), &dir.join("b/shot.dng")]);
+    }
+
+    #[test]
+    fn test_undecodable_identical_files() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("broken.jpg"), b"not an image").unwrap();
+        fs::write(dir.join("broken copy.jpg"), b"not an image").unwrap();
+        fs::write(dir.join("other.jpg"), b"not an image either").unwrap();
+
+        let report = find_duplicates(
+            &index_images_in_folder(dir.to_path_buf()),
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+
+        assert_eq!(report.groups.len(), 1);
+        let mut files: Vec<_> = report.groups[0].files.iter().map(|f| &f.path).collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [&dir.join("broken copy.jpg"), &dir.join("broken.jpg")]
+        );
+        assert!(report.groups[0]
+            .files
+            .iter()
+            .all(|f| f.resolution.is_none()));
+    }
+
+    #[test]
+    fn test_primary_root() {
+        let temp = TempDir::new().unwrap();
+        let roots = vec![temp.path().join("backup"), temp.path().join("library")];
+        for root in &roots {
+            fs::create_dir_all(root).unwrap();
+            fs::copy(
+                "test-data/orientation/orientation-1.jpg",
+                root.join("a.jpg"),
+            )
+            .unwrap();
+        }
+
+        let options = ScanOptions {
+            keeper_policy: KeeperPolicy {
+                primary_root: Some(roots[1].clone()),
+                ..KeeperPolicy::default()
+            },
+            roots: roots.clone(),
+            ..ScanOptions::default()
+        };
+        let (images, _) = index_media(&roots, &IndexerOptions::new());
+        let report =
+            find_duplicates(&images, &options, &mut FingerprintCache::in_memory()).unwrap();
+
+        let group = &report.groups[0];
+        assert_eq!(group.keeper, roots[1].join("a.jpg"));
+        assert_eq!(group.files[1].root.as_ref(), Some(&roots[0]));
+    }
+
+    #[test]
+    fn test_identical_videos() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let clip = crate::video::tests::mp4(10.0, (1280, 720), false);
+        fs::write(dir.join("clip.mp4"), &clip).unwrap();
+        fs::write(dir.join("clip copy.mp4"), &clip).unwrap();
+        fs::write(
+            dir.join("other.mov"),
+            crate::video::tests::mp4(4.0, (640, 480), false),
+        )
+        .unwrap();
+        // Not videos, but identical copies all the same
+        fs::write(dir.join("broken.mp4"), b"not a video").unwrap();
+        fs::write(dir.join("broken copy.mp4"), b"not a video").unwrap();
+        fs::write(dir.join("garbage.mp4"), b"not a video either").unwrap();
+
+        let mut paths = index_videos_in_folder(dir.to_path_buf());
+        paths.sort();
+        let groups = find_video_duplicates(
+            &paths,
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+
+        assert_eq!(groups.len(), 2);
+        let broken = &groups[0];
+        assert_eq!(broken.files.len(), 2);
+        assert!(broken.files.iter().all(|file| file.resolution.is_none()));
+        let group = &groups[1];
+        assert_eq!(group.keeper, dir.join("clip copy.mp4"));
+        assert_eq!(group.files.len(), 2);
+        for file in &group.files {
+            assert_eq!(file.resolution, Some((1280, 720)));
+            assert_eq!(file.score, Some(1.0));
+            assert_eq!(
+                file.date_time_original.as_deref(),
+                Some("2024:05:01 12:00:00")
+            );
+        }
+    }
+
+    #[test]
+    fn test_group_videos() {
+        let paths: Vec<PathBuf> = ["a.mp4", "b.mp4", "c.mp4", "d.mp4", "e.mp4", "f.mp4"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let metadata = |duration, resolution| {
+            Some(VideoMetadata {
+                duration,
+                resolution,
+                creation_date: None,
+            })
+        };
+        let hashes = |bits| Some(vec![PerceptualHash(bits); video::FRAME_COUNT]);
+        let copies = HashMap::from([(paths[5].clone(), vec![PathBuf::from("f copy.mp4")])]);
+
+        let groups = group_videos(
+            paths.clone(),
+            vec![
+                metadata(10.0, (1920, 1080)),
+                // Re-encoded smaller, changing a few bits of every fingerprint
+                metadata(10.1, (1280, 720)),
+                // Different frames
+                metadata(10.0, (1920, 1080)),
+                // Longer
+                metadata(30.0, (1920, 1080)),
+                // Cropped square
+                metadata(10.0, (1080, 1080)),
+                // Not fingerprinted
+                metadata(10.0, (1920, 1080)),
+            ],
+            &[
+                hashes(0),
+                hashes(0b111),
+                hashes(0xffff),
+                hashes(0),
+                hashes(0),
+                None,
+            ],
+            copies,
+            &ScanOptions::default(),
+        );
+
+        assert_eq!(groups.len(), 2);
+        let files: Vec<_> = groups[0].files.iter().map(|file| &file.path).collect();
+        assert_eq!(files, [&paths[0], &paths[1]]);
+        assert_eq!(groups[0].files[1].score, Some(1.0 - 3.0 / 64.0));
+        let mut files: Vec<_> = groups[1].files.iter().map(|file| &file.path).collect();
+        files.sort();
+        assert_eq!(files, [&PathBuf::from("f copy.mp4"), &paths[5]]);
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn test_reencoded_videos() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let clip = encode(dir, "clip.mp4", "testsrc=size=640x360", None);
+        let smaller = encode(dir, "smaller.mp4", "testsrc=size=640x360", Some(320));
+        encode(dir, "other.mp4", "mandelbrot=size=640x360", None);
+
+        let mut paths = index_videos_in_folder(dir.to_path_buf());
+        paths.sort();
+        let groups = find_video_duplicates(
+            &paths,
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keeper, clip);
+        let files: Vec<_> = groups[0].files.iter().map(|file| &file.path).collect();
+        assert_eq!(files, [&clip, &smaller]);
+    }
+
+    #[test]
+    fn test_live_photo_is_one_asset() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let id = "A1B2C3D4-0000-4000-8000-000000000001";
+        let still = crate::motion::tests::live_photo_jpeg(id);
+        for folder in ["a", "b", "c"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+            fs::write(dir.join(folder).join("IMG_0001.JPG"), &still).unwrap();
+        }
+        // The identical videos follow their stills instead of forming a group of their own
+        for folder in ["a", "c"] {
+            fs::write(
+                dir.join(folder).join("IMG_0001.MOV"),
+                crate::video::tests::live_photo_mov(id),
+            )
+            .unwrap();
+        }
+
+        let report = find_media_duplicates(
+            &index_images_in_folder(dir.to_path_buf()),
+            &index_videos_in_folder(dir.to_path_buf()),
+            &ScanOptions::default(),
+            &mut FingerprintCache::in_memory(),
+        )
+        .unwrap();
+
+        assert_eq!(report.groups.len(), 1);
+        let group = &report.groups[0];
+        assert_eq!(group.files.len(), 3);
+        let keeper = &group.files[0];
+        assert_eq!(keeper.path, group.keeper);
+        assert_eq!(keeper.companions, vec![keeper.path.with_extension("MOV")]);
+        assert_eq!(group.files[2].path, dir.join("b/IMG_0001.JPG"));
+        assert!(group.files[2].companions.is_empty());
     }
 
     fn get_test_images() -> Vec<PathBuf> {
@@ -14,6 +14,10 @@ pub enum AppError {
     #[error("Different aspect ratios")]
     DifferentAspectRatio,
 
+    #[cfg(feature = "heic")]
+    #[error("HEIF error: {0}")]
+    HeifError(#[from] libheif_rs::HeifError),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -26,6 +30,51 @@ pub enum AppError {
     #[error("Invalid hash chunk size {0}: Should be between 0.0 and 1.0")]
     InvalidHashChunkSize(f32),
 
+    #[error("Image too small to compare: {0}x{1}")]
+    ImageTooSmall(u32, u32),
+
+    #[error("Unknown similarity metric: {0}")]
+    UnknownMetric(String),
+
+    #[error("Unknown keeper rule: {0}")]
+    UnknownKeeperRule(String),
+
+    #[error("XML error: {0}")]
+    XmlError(#[from] quick_xml::Error),
+
+    #[error("Invalid XMP: {0}")]
+    InvalidXmp(String),
+
+    #[error("Unknown conflict rule: {0}")]
+    UnknownConflictRule(String),
+
+    #[error("File already exists: {}", .0.display())]
+    FileExists(std::path::PathBuf),
+
+    #[error("File changed since it was moved: {}", .0.display())]
+    ContentChanged(std::path::PathBuf),
+
+    #[error("Unknown link mode: {0}")]
+    UnknownLinkMode(String),
+
+    #[error("No embedded preview that can be decoded")]
+    MissingPreview,
+
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("Unknown symlink policy: {0}")]
+    UnknownSymlinkPolicy(String),
+
+    #[error("Invalid video: {0}")]
+    InvalidVideo(String),
+
+    #[error("ffmpeg error: {0}")]
+    FfmpegError(String),
+
+    #[error("Scan cancelled")]
+    Cancelled,
+
     #[error("Unknown error")]
     Unknown,
 }
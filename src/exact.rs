@@ -0,0 +1,175 @@
+//! Exact duplicate detection.
+//! Finds bit-identical files without decoding them. Files are grouped by size, then by a hash of
+//! their first and last blocks, and only files that still collide have their full contents hashed.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use rayon::prelude::*;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+use crate::error::AppError;
+
+/// Size of the blocks read from each end of a file for the partial hash.
+const BLOCK_SIZE: u64 = 4096;
+
+/// Returns the groups of files with identical contents. Each group is sorted, as are the groups.
+pub fn find_exact_duplicates(paths: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    let by_size = split_by(vec![paths.to_vec()], |path| Ok(fs::metadata(path)?.len()));
+    debug!("{} groups of files with the same size", by_size.len());
+
+    let by_partial_hash = split_by(by_size, partial_hash);
+    debug!(
+        "{} groups of files with the same first and last blocks",
+        by_partial_hash.len()
+    );
+
+    let mut groups = split_by(by_partial_hash, file_hash);
+
+    for group in &mut groups {
+        group.sort();
+    }
+    groups.sort();
+
+    groups
+}
+
+/// Returns the xxh3 hash of the entire contents of a file.
+pub fn file_hash(path: &Path) -> Result<u64, AppError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+
+    Ok(hasher.digest())
+}
+
+/// Compare the contents of two files byte by byte.
+pub fn same_contents(a: &Path, b: &Path) -> Result<bool, AppError> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut reader_a = BufReader::new(File::open(a)?);
+    let mut reader_b = BufReader::new(File::open(b)?);
+    let mut buffer_a = vec![0; 64 * 1024];
+    let mut buffer_b = vec![0; 64 * 1024];
+
+    loop {
+        let n = reader_a.read(&mut buffer_a)?;
+        if n == 0 {
+            return Ok(reader_b.read(&mut buffer_b)? == 0);
+        }
+        reader_b.read_exact(&mut buffer_b[..n])?;
+        if buffer_a[..n] != buffer_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+// Hash of the first and last blocks of a file, which is the whole file for small files
+fn partial_hash(path: &Path) -> Result<u64, AppError> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let mut data = Vec::with_capacity(2 * BLOCK_SIZE as usize);
+    (&mut file).take(BLOCK_SIZE).read_to_end(&mut data)?;
+    if size > BLOCK_SIZE {
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(BLOCK_SIZE).max(BLOCK_SIZE),
+        ))?;
+        file.take(BLOCK_SIZE).read_to_end(&mut data)?;
+    }
+
+    Ok(xxh3_64(&data))
+}
+
+// Split each group by key, keeping only groups with more than one member. Files that can't be
+// read can't be shown to be duplicates, so they are dropped. The keys of all files are computed in
+// parallel, then the files are grouped in their original order.
+fn split_by<K: Hash + Eq + Send>(
+    groups: Vec<Vec<PathBuf>>,
+    key: impl Fn(&Path) -> Result<K, AppError> + Sync,
+) -> Vec<Vec<PathBuf>> {
+    let paths: Vec<(usize, PathBuf)> = groups
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, group)| group.into_iter().map(move |path| (i, path)))
+        .collect();
+    let keys: Vec<_> = paths.par_iter().map(|(_, path)| key(path)).collect();
+
+    let mut groups: HashMap<(usize, K), Vec<PathBuf>> = HashMap::new();
+    for ((i, path), key) in paths.into_iter().zip(keys) {
+        match key {
+            Ok(k) => groups.entry((i, k)).or_default().push(path),
+            Err(e) => warn!("Skipping file {}: {}", path.display(), e),
+        }
+    }
+
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::index_images_in_folder;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_exact_duplicates() {
+        let paths = index_images_in_folder(PathBuf::from("test-data"));
+        let groups = find_exact_duplicates(&paths);
+
+        // house.jpg and house-duplicate.jpg have the same size but different metadata
+        assert_eq!(
+            groups,
+            vec![vec![
+                PathBuf::from("test-data/01/coffee.jpeg"),
+                PathBuf::from("test-data/02/coffee.jpeg"),
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_same_ends_different_middle() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+
+        let mut contents = vec![0u8; 3 * BLOCK_SIZE as usize];
+        let a = dir.join("a.bin");
+        let b = dir.join("b.bin");
+        let c = dir.join("c.bin");
+        fs::write(&a, &contents).unwrap();
+        fs::write(&b, &contents).unwrap();
+        contents[BLOCK_SIZE as usize + 1] = 1;
+        fs::write(&c, &contents).unwrap();
+
+        assert_eq!(
+            partial_hash(&a).unwrap(),
+            partial_hash(&c).unwrap(),
+            "only the middle block differs"
+        );
+        assert!(same_contents(&a, &b).unwrap());
+        assert!(!same_contents(&a, &c).unwrap());
+        assert_eq!(
+            find_exact_duplicates(&[a.clone(), b.clone(), c]),
+            vec![vec![a, b]]
+        );
+    }
+}
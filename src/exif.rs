@@ -0,0 +1,99 @@
+//! Summary of the EXIF fields used to rank and merge duplicates.
+
+use rexif::{ExifData, ExifTag, TagValue};
+use serde::{Deserialize, Serialize};
+
+/// A latitude/longitude pair in decimal degrees, negative for south and west.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExifSummary {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// Capture date as written by the camera, e.g. `2017:03:12 17:50:35`.
+    pub date_time_original: Option<String>,
+    pub gps: Option<GpsPosition>,
+}
+
+impl ExifSummary {
+    pub fn from_exif(exif: &ExifData) -> ExifSummary {
+        let latitude = degrees(exif, ExifTag::GPSLatitude, ExifTag::GPSLatitudeRef, 'S');
+        let longitude = degrees(exif, ExifTag::GPSLongitude, ExifTag::GPSLongitudeRef, 'W');
+
+        ExifSummary {
+            make: ascii(exif, ExifTag::Make),
+            model: ascii(exif, ExifTag::Model),
+            date_time_original: ascii(exif, ExifTag::DateTimeOriginal),
+            gps: latitude
+                .zip(longitude)
+                .map(|(latitude, longitude)| GpsPosition {
+                    latitude,
+                    longitude,
+                }),
+        }
+    }
+}
+
+fn value(exif: &ExifData, tag: ExifTag) -> Option<&TagValue> {
+    exif.entries
+        .iter()
+        .find(|entry| entry.tag == tag)
+        .map(|entry| &entry.value)
+}
+
+fn ascii(exif: &ExifData, tag: ExifTag) -> Option<String> {
+    match value(exif, tag)? {
+        TagValue::Ascii(s) => {
+            let s = s.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!s.is_empty()).then(|| s.to_string())
+        }
+        _ => None,
+    }
+}
+
+// Convert a degrees/minutes/seconds triple to decimal degrees
+fn degrees(exif: &ExifData, tag: ExifTag, ref_tag: ExifTag, negative: char) -> Option<f64> {
+    let TagValue::URational(dms) = value(exif, tag)? else {
+        return None;
+    };
+    if dms.len() < 3 || dms.iter().any(|r| r.denominator == 0) {
+        return None;
+    }
+
+    let degrees = dms[0].value() + dms[1].value() / 60.0 + dms[2].value() / 3600.0;
+    let sign = match ascii(exif, ref_tag) {
+        Some(r) if r.starts_with(negative) => -1.0,
+        _ => 1.0,
+    };
+
+    Some(sign * degrees)
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_from_exif() {
+        let exif = rexif::parse_file(PathBuf::from("test-data/01/house.jpg")).unwrap();
+        let summary = ExifSummary::from_exif(&exif);
+
+        assert_eq!(summary.make.as_deref(), Some("Apple"));
+        assert_eq!(
+            summary.date_time_original.as_deref(),
+            Some("2017:03:12 17:50:35")
+        );
+
+        // Edinburgh
+        let gps = summary.gps.unwrap();
+        assert!((gps.latitude - 55.96).abs() < 0.01);
+        assert!((gps.longitude + 3.28).abs() < 0.01);
+    }
+}
@@ -0,0 +1,235 @@
+//! File type detection from the first bytes of a file.
+//! Extensions can't always be trusted: libraries hold backups such as `IMG_0001.JPG.bak`, exports
+//! without an extension and PNGs saved as `.jpg`.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::AppError;
+use crate::raw;
+use crate::video;
+
+/// Number of bytes read to detect the type of a file.
+const HEADER_LEN: usize = 32;
+
+/// ISO media brands of HEIF images, as opposed to videos.
+const HEIF_BRANDS: [&[u8; 4]; 8] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+/// ISO media brands of AVIF images, which can't be decoded. AVIF files often list `mif1` too.
+const AVIF_BRANDS: [&[u8; 4]; 2] = [b"avif", b"avis"];
+
+/// QuickTime atoms that may start a movie written without a file type atom.
+const QUICKTIME_ATOMS: [&[u8; 4]; 5] = [b"moov", b"mdat", b"wide", b"free", b"skip"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Jpeg,
+    Png,
+    Gif,
+    Bmp,
+    /// TIFF, including the TIFF-based RAW formats.
+    Tiff,
+    Webp,
+    Heif,
+    /// MP4 or QuickTime movie.
+    Video,
+}
+
+impl FileType {
+    pub const ALL: [FileType; 8] = [
+        FileType::Jpeg,
+        FileType::Png,
+        FileType::Gif,
+        FileType::Bmp,
+        FileType::Tiff,
+        FileType::Webp,
+        FileType::Heif,
+        FileType::Video,
+    ];
+
+    /// Detect the type of a file from its content. Returns None for other types.
+    pub fn detect(path: &Path) -> Result<Option<FileType>, AppError> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        File::open(path)?
+            .take(HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+
+        Ok(FileType::from_bytes(&header))
+    }
+
+    /// Detect the type of a file from its first bytes.
+    pub fn from_bytes(header: &[u8]) -> Option<FileType> {
+        let at = |offset: usize, signature: &[u8]| {
+            header
+                .get(offset..offset + signature.len())
+                .is_some_and(|bytes| bytes == signature)
+        };
+
+        if at(0, &[0xff, 0xd8, 0xff]) {
+            Some(FileType::Jpeg)
+        } else if at(0, b"\x89PNG\r\n\x1a\n") {
+            Some(FileType::Png)
+        } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+            Some(FileType::Gif)
+        } else if at(0, b"BM") {
+            Some(FileType::Bmp)
+        } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+            Some(FileType::Tiff)
+        } else if at(0, b"RIFF") && at(8, b"WEBP") {
+            Some(FileType::Webp)
+        } else if at(4, b"ftyp") {
+            // The major brand, then the compatible brands after the minor version
+            let brands = header.get(8..).unwrap_or_default();
+            let has_brand = |known: &[&[u8; 4]]| {
+                brands
+                    .chunks_exact(4)
+                    .enumerate()
+                    .filter(|(i, _)| *i != 1)
+                    .any(|(_, brand)| known.iter().any(|known| brand == *known))
+            };
+            if has_brand(&AVIF_BRANDS) {
+                None
+            } else if has_brand(&HEIF_BRANDS) {
+                Some(FileType::Heif)
+            } else {
+                Some(FileType::Video)
+            }
+        } else if QUICKTIME_ATOMS.iter().any(|atom| at(4, *atom)) {
+            Some(FileType::Video)
+        } else {
+            None
+        }
+    }
+
+    /// The type a file's extension claims it has.
+    pub fn from_extension(path: &Path) -> Option<FileType> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        FileType::ALL
+            .into_iter()
+            .find(|file_type| file_type.has_extension(&extension))
+    }
+
+    /// Returns true if `extension`, in lower case, is one used for files of this type.
+    pub fn has_extension(&self, extension: &str) -> bool {
+        match self {
+            FileType::Jpeg => ["jpg", "jpeg"].contains(&extension),
+            FileType::Png => extension == "png",
+            FileType::Gif => extension == "gif",
+            FileType::Bmp => extension == "bmp",
+            FileType::Tiff => {
+                ["tif", "tiff"].contains(&extension) || raw::EXTENSIONS.contains(&extension)
+            }
+            FileType::Webp => extension == "webp",
+            FileType::Heif => ["heic", "heif"].contains(&extension),
+            FileType::Video => video::EXTENSIONS.contains(&extension),
+        }
+    }
+
+    /// Returns true if files of this type can be decoded as images. HEIF needs the `heic` feature.
+    pub fn is_image(&self) -> bool {
+        match self {
+            FileType::Heif => cfg!(feature = "heic"),
+            FileType::Video => false,
+            FileType::Jpeg
+            | FileType::Png
+            | FileType::Gif
+            | FileType::Bmp
+            | FileType::Tiff
+            | FileType::Webp => true,
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileType::Jpeg => "JPEG",
+            FileType::Png => "PNG",
+            FileType::Gif => "GIF",
+            FileType::Bmp => "BMP",
+            FileType::Tiff => "TIFF",
+            FileType::Webp => "WebP",
+            FileType::Heif => "HEIF",
+            FileType::Video => "video",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Returns the detected type of a file whose extension is missing or belongs to another type.
+pub fn extension_mismatch(path: &Path) -> Option<FileType> {
+    let detected = FileType::detect(path).ok()??;
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+
+    (!detected.has_extension(&extension)).then_some(detected)
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_detect() {
+        let jpeg = PathBuf::from("test-data/orientation/orientation-1.jpg");
+        assert_eq!(FileType::detect(&jpeg).unwrap(), Some(FileType::Jpeg));
+        assert_eq!(FileType::from_extension(&jpeg), Some(FileType::Jpeg));
+
+        assert_eq!(
+            FileType::from_bytes(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic"),
+            Some(FileType::Heif)
+        );
+        // AVIF images aren't videos, nor HEIC despite listing `mif1`
+        assert_eq!(
+            FileType::from_bytes(b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf"),
+            None
+        );
+        assert_eq!(
+            FileType::from_bytes(b"\0\0\0\x14ftypqt  \0\0\0\0qt  "),
+            Some(FileType::Video)
+        );
+        assert_eq!(
+            FileType::from_bytes(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(FileType::Webp)
+        );
+        assert_eq!(FileType::from_bytes(b"<?xml version"), None);
+        assert_eq!(
+            FileType::from_extension(Path::new("DSC_0001.NEF")),
+            Some(FileType::Tiff)
+        );
+    }
+
+    #[test]
+    fn test_extension_mismatch() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let jpeg = fs::read("test-data/orientation/orientation-1.jpg").unwrap();
+        for name in [
+            "IMG_0001.JPG",
+            "IMG_0001.JPG.bak",
+            "IMG_0001",
+            "IMG_0001.png",
+        ] {
+            fs::write(dir.join(name), &jpeg).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), b"not an image").unwrap();
+
+        assert_eq!(extension_mismatch(&dir.join("IMG_0001.JPG")), None);
+        for name in ["IMG_0001.JPG.bak", "IMG_0001", "IMG_0001.png"] {
+            assert_eq!(extension_mismatch(&dir.join(name)), Some(FileType::Jpeg));
+        }
+        assert_eq!(extension_mismatch(&dir.join("notes.txt")), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! Perceptual image fingerprints.
+//! Unlike a content hash, similar looking images produce similar fingerprints, so resized or
+//! re-encoded copies can be found by comparing the Hamming distance between them.
+
+use std::f64::consts::PI;
+use std::fmt;
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+
+/// Side of the square thumbnail the fingerprint bits are derived from.
+const HASH_SIZE: u32 = 8;
+
+/// Side of the thumbnail the DCT is computed over.
+const DCT_SIZE: u32 = 32;
+
+/// A 64 bit perceptual hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PerceptualHash(pub u64);
+
+impl PerceptualHash {
+    /// Returns the number of bits that differ between two hashes.
+    pub fn distance(&self, other: &PerceptualHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl fmt::Display for PerceptualHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Pixels brighter than the mean (aHash).
+    Average,
+    /// Pixels brighter than their right hand neighbour (dHash).
+    Difference,
+    /// Low frequency DCT coefficients above the median (pHash).
+    Dct,
+}
+
+/// All perceptual hashes of an image, computed from a single decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprints {
+    pub average: PerceptualHash,
+    pub difference: PerceptualHash,
+    pub dct: PerceptualHash,
+}
+
+impl Fingerprints {
+    pub fn from_image(img: &DynamicImage) -> Fingerprints {
+        let gray = img.to_luma8();
+        Fingerprints {
+            average: average_hash(&gray),
+            difference: difference_hash(&gray),
+            dct: dct_hash(&gray),
+        }
+    }
+
+    /// Returns the hash computed with `algorithm`.
+    pub fn get(&self, algorithm: HashAlgorithm) -> PerceptualHash {
+        match algorithm {
+            HashAlgorithm::Average => self.average,
+            HashAlgorithm::Difference => self.difference,
+            HashAlgorithm::Dct => self.dct,
+        }
+    }
+}
+
+/// Compute the perceptual hash of an image with the given algorithm.
+pub fn perceptual_hash(img: &DynamicImage, algorithm: HashAlgorithm) -> PerceptualHash {
+    let gray = img.to_luma8();
+    match algorithm {
+        HashAlgorithm::Average => average_hash(&gray),
+        HashAlgorithm::Difference => difference_hash(&gray),
+        HashAlgorithm::Dct => dct_hash(&gray),
+    }
+}
+
+fn average_hash(gray: &GrayImage) -> PerceptualHash {
+    let small = imageops::resize(gray, HASH_SIZE, HASH_SIZE, FilterType::Triangle);
+    let mean = small.pixels().map(|p| p.0[0] as u32).sum::<u32>() / (HASH_SIZE * HASH_SIZE);
+
+    from_bits(small.pixels().map(|p| p.0[0] as u32 > mean))
+}
+
+fn difference_hash(gray: &GrayImage) -> PerceptualHash {
+    // One extra column so that each row yields HASH_SIZE comparisons
+    let small = imageops::resize(gray, HASH_SIZE + 1, HASH_SIZE, FilterType::Triangle);
+
+    from_bits((0..HASH_SIZE).flat_map(|y| {
+        let small = &small;
+        (0..HASH_SIZE).map(move |x| small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0])
+    }))
+}
+
+fn dct_hash(gray: &GrayImage) -> PerceptualHash {
+    let small = imageops::resize(gray, DCT_SIZE, DCT_SIZE, FilterType::Triangle);
+    let n = DCT_SIZE as usize;
+    let k = HASH_SIZE as usize;
+
+    // cos_table[u][x] = cos((2x + 1) * u * pi / 2n) for the frequencies that are kept
+    let cos_table: Vec<Vec<f64>> = (0..k)
+        .map(|u| {
+            (0..n)
+                .map(|x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * n) as f64).cos())
+                .collect()
+        })
+        .collect();
+
+    let mut coefficients = Vec::with_capacity(k * k);
+    for v in 0..k {
+        for u in 0..k {
+            let mut sum = 0.0;
+            for y in 0..n {
+                for x in 0..n {
+                    let pixel = small.get_pixel(x as u32, y as u32).0[0] as f64;
+                    sum += pixel * cos_table[u][x] * cos_table[v][y];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    // The DC term reflects overall brightness only, so leave it out of the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    from_bits(coefficients.iter().map(|&c| c > median))
+}
+
+fn from_bits(bits: impl Iterator<Item = bool>) -> PerceptualHash {
+    PerceptualHash(bits.fold(0u64, |hash, bit| (hash << 1) | bit as u64))
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb, RgbImage};
+
+    #[test]
+    fn test_distance() {
+        let a = PerceptualHash(0b1011);
+        let b = PerceptualHash(0b0110);
+        assert_eq!(a.distance(&b), 3);
+        assert_eq!(a.distance(&a), 0);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(PerceptualHash(0xab).to_string(), "00000000000000ab");
+    }
+
+    #[test]
+    fn test_resized_image_has_same_fingerprints() {
+        let img = pattern(256, 192);
+        let small = img.resize_exact(64, 48, FilterType::Lanczos3);
+
+        let a = Fingerprints::from_image(&img);
+        let b = Fingerprints::from_image(&small);
+
+        assert!(a.average.distance(&b.average) <= 2);
+        assert!(a.difference.distance(&b.difference) <= 2);
+        assert!(a.dct.distance(&b.dct) <= 2);
+    }
+
+    #[test]
+    fn test_different_images_have_different_fingerprints() {
+        let img = pattern(256, 192);
+        let flipped = img.fliph();
+
+        for algorithm in [
+            HashAlgorithm::Average,
+            HashAlgorithm::Difference,
+            HashAlgorithm::Dct,
+        ] {
+            let a = perceptual_hash(&img, algorithm);
+            let b = perceptual_hash(&flipped, algorithm);
+            assert!(a.distance(&b) > 10, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_flat_image_difference_hash_is_zero() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(32, 32, Luma([128])));
+        assert_eq!(
+            perceptual_hash(&img, HashAlgorithm::Difference),
+            PerceptualHash(0)
+        );
+    }
+
+    // A pattern of blocks with pseudo-random intensities
+    fn pattern(width: u32, height: u32) -> DynamicImage {
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            let v = ((x * 8 / width) * 53 + (y * 8 / height) * 97) % 256;
+            Rgb([v as u8, (v / 2) as u8, 255 - v as u8])
+        });
+        DynamicImage::ImageRgb8(img)
+    }
+}
@@ -0,0 +1,185 @@
+//! `.gitignore`-style path patterns.
+//! `*` and `?` match within a path component, `**` across components and `[...]` a character
+//! class. A pattern without a slash matches a name at any depth, one with a slash is anchored to
+//! the folder it is relative to, and a trailing slash only matches folders. Matching ignores case,
+//! as photo libraries mix `.JPG` and `.jpg`.
+
+use std::fs;
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone)]
+pub struct Glob {
+    regex: Regex,
+    /// Only matches folders.
+    dir_only: bool,
+    /// Written with a leading `!`, re-including what an earlier pattern excluded.
+    pub negated: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, AppError> {
+        let (negated, rest) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (dir_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        if rest.is_empty() {
+            return Err(AppError::InvalidPattern(pattern.to_string()));
+        }
+
+        let anchored = rest.contains('/');
+        let mut regex = String::from("^");
+        if !anchored {
+            regex.push_str("(?:.*/)?");
+        }
+        regex.push_str(&translate(rest.trim_start_matches('/'), pattern)?);
+        regex.push('$');
+
+        let regex = RegexBuilder::new(&regex)
+            .case_insensitive(true)
+            .build()
+            .map_err(|_| AppError::InvalidPattern(pattern.to_string()))?;
+
+        Ok(Glob {
+            regex,
+            dir_only,
+            negated,
+        })
+    }
+
+    /// Returns true if the pattern matches `relative`, a path relative to the folder the pattern
+    /// applies to.
+    pub fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path: Vec<_> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+
+        self.regex.is_match(&path.join("/"))
+    }
+}
+
+/// Read the patterns of an ignore file, skipping blank lines and `#` comments.
+pub fn read_ignore_file(path: &Path) -> Result<Vec<Glob>, AppError> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Glob::new)
+        .collect()
+}
+
+/// Returns the last of `globs` to match the path, which decides whether an ignore file excludes
+/// it.
+pub fn last_match<'a>(globs: &'a [Glob], relative: &Path, is_dir: bool) -> Option<&'a Glob> {
+    globs
+        .iter()
+        .rev()
+        .find(|glob| glob.matches(relative, is_dir))
+}
+
+// The regular expression for a pattern without its leading `!` and trailing slash
+fn translate(glob: &str, pattern: &str) -> Result<String, AppError> {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // Any number of folders, including none
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::from("[");
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('\\') => class.push_str("\\\\"),
+                        Some('[') => class.push_str("\\["),
+                        Some(c) => class.push(c),
+                        None => return Err(AppError::InvalidPattern(pattern.to_string())),
+                    }
+                }
+                class.push(']');
+                regex.push_str(&class);
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    Ok(regex)
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let glob = |pattern| Glob::new(pattern).unwrap();
+        let file = |glob: &Glob, path: &str| glob.matches(Path::new(path), false);
+
+        // Names match at any depth, ignoring case
+        assert!(file(&glob("*.jpg"), "IMG_0001.JPG"));
+        assert!(file(&glob("*.jpg"), "2023/holiday/IMG_0001.jpg"));
+        assert!(!file(&glob("*.jpg"), "IMG_0001.jpg.bak"));
+        assert!(file(&glob("IMG_????.jpg"), "a/IMG_0001.jpg"));
+        assert!(file(&glob("IMG_[0-4]*.jpg"), "IMG_0001.jpg"));
+        assert!(!file(&glob("IMG_[!0-4]*.jpg"), "IMG_0001.jpg"));
+
+        // Slashes anchor the pattern
+        assert!(file(&glob("2023/*.jpg"), "2023/a.jpg"));
+        assert!(!file(&glob("2023/*.jpg"), "b/2023/a.jpg"));
+        assert!(!file(&glob("2023/*.jpg"), "2023/holiday/a.jpg"));
+        assert!(file(&glob("/2023/**/*.jpg"), "2023/holiday/a.jpg"));
+        assert!(file(&glob("**/@eaDir"), "a/b/@eaDir"));
+
+        // A trailing slash only matches folders
+        let folder = glob("@eaDir/");
+        assert!(folder.matches(Path::new("photos/@eaDir"), true));
+        assert!(!folder.matches(Path::new("photos/@eaDir"), false));
+
+        assert!(Glob::new("[a-").is_err());
+        assert!(Glob::new("!").is_err());
+    }
+
+    #[test]
+    fn test_last_match_wins() {
+        let globs: Vec<Glob> = ["*.jpg", "!keep/*.jpg", "keep/private.jpg"]
+            .iter()
+            .map(|pattern| Glob::new(pattern).unwrap())
+            .collect();
+
+        let ignored =
+            |path| last_match(&globs, Path::new(path), false).is_some_and(|glob| !glob.negated);
+
+        assert!(ignored("a.jpg"));
+        assert!(!ignored("keep/a.jpg"));
+        assert!(ignored("keep/private.jpg"));
+        assert!(!ignored("a.png"));
+    }
+}
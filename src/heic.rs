@@ -0,0 +1,98 @@
+//! HEIC/HEIF decoding with libheif.
+//! libheif applies the rotation and mirroring stored in the file while decoding, so the images and
+//! dimensions returned here are already upright.
+
+use std::fs;
+use std::path::Path;
+
+use image::{DynamicImage, RgbImage};
+use libheif_rs::{ColorSpace, HeifContext, ItemId, LibHeif, RgbChroma};
+
+use crate::error::AppError;
+
+pub const EXTENSIONS: [&str; 2] = ["heic", "heif"];
+
+/// Returns true if the file has a HEIC or HEIF extension.
+pub fn is_heif(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Decode the primary image to 8-bit RGB.
+pub fn decode(path: &Path) -> Result<DynamicImage, AppError> {
+    let data = fs::read(path)?;
+    let context = HeifContext::read_from_bytes(&data)?;
+    let handle = context.primary_image_handle()?;
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| AppError::UnsupportedType("HEIF image without RGB data".to_string()))?;
+
+    // Rows may be padded beyond the width
+    let row = plane.width as usize * 3;
+    let mut pixels = Vec::with_capacity(row * plane.height as usize);
+    for y in 0..plane.height as usize {
+        let start = y * plane.stride;
+        pixels.extend_from_slice(&plane.data[start..start + row]);
+    }
+
+    let buffer = RgbImage::from_raw(plane.width, plane.height, pixels)
+        .expect("pixels match the plane dimensions");
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+/// Returns the width and height of the primary image with its transformations applied.
+pub fn dimensions(path: &Path) -> Result<(u32, u32), AppError> {
+    let data = fs::read(path)?;
+    let context = HeifContext::read_from_bytes(&data)?;
+    let handle = context.primary_image_handle()?;
+
+    Ok((handle.width(), handle.height()))
+}
+
+/// Returns the TIFF-encoded EXIF data of the primary image, if it has any.
+pub fn exif(path: &Path) -> Result<Option<Vec<u8>>, AppError> {
+    let data = fs::read(path)?;
+    let context = HeifContext::read_from_bytes(&data)?;
+    let handle = context.primary_image_handle()?;
+
+    let mut ids: [ItemId; 1] = [0];
+    if handle.metadata_block_ids(&mut ids, b"Exif") == 0 {
+        return Ok(None);
+    }
+    let block = handle.metadata(ids[0])?;
+
+    // The block starts with the offset of the TIFF header from the end of the offset itself
+    let Some(offset) = block.get(..4) else {
+        return Ok(None);
+    };
+    let offset = u32::from_be_bytes(offset.try_into().expect("four bytes")) as usize + 4;
+
+    Ok(block.get(offset..).map(<[u8]>::to_vec))
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_decode() {
+        let path = PathBuf::from("test-data/01/01-sub/magazine.heic");
+        assert!(is_heif(&path));
+
+        let img = decode(&path).unwrap();
+        assert_eq!((img.width(), img.height()), dimensions(&path).unwrap());
+    }
+
+    #[test]
+    fn test_is_heif() {
+        assert!(is_heif(Path::new("IMG_0001.HEIC")));
+        assert!(!is_heif(Path::new("IMG_0001.JPG")));
+    }
+}
@@ -6,16 +6,34 @@ use std::path::PathBuf;
 use image::{DynamicImage, ImageReader};
 use log::debug;
 use rexif::{ExifTag, TagValue};
+use serde::{Deserialize, Serialize};
 use xxhash_rust::xxh3::xxh3_64;
 
 use crate::error::AppError;
+use crate::exif::ExifSummary;
+#[cfg(feature = "heic")]
+use crate::filetype::FileType;
+use crate::fingerprint::{self, Fingerprints, HashAlgorithm, PerceptualHash};
+#[cfg(feature = "heic")]
+use crate::heic;
+use crate::raw;
 
 #[derive(Debug)]
 pub struct Image {
     pub path: PathBuf,
 }
 
-#[derive(Debug)]
+/// The pixels covered by `Image::hash`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashMode {
+    /// The top-left corner, as a fraction of the width and height.
+    Chunk(f32),
+    /// The whole orientation-normalised image. Files whose metadata differs but whose pixels are
+    /// identical share this hash.
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Orientation {
     Normal,
     MirrorHorizontal,
@@ -28,6 +46,19 @@ pub enum Orientation {
     Unknown,
 }
 
+impl Orientation {
+    /// Returns true if the width and height are swapped when the orientation is applied.
+    pub fn swaps_dimensions(&self) -> bool {
+        matches!(
+            self,
+            Orientation::MirroredHorizontallyAndRotated270
+                | Orientation::Rotated90
+                | Orientation::MirroredHorizontallyAndRotated90
+                | Orientation::Rotated270
+        )
+    }
+}
+
 impl Image {
     /// Create an image from a file path.
     pub fn from_path(path: &PathBuf) -> Result<Image, AppError> {
@@ -37,10 +68,15 @@ impl Image {
     }
 
     pub fn valid_extensions() -> Vec<&'static str> {
-        vec!["png", "jpg", "jpeg", "gif", "bmp", "tiff", "webp"]
+        let mut extensions = vec!["png", "jpg", "jpeg", "gif", "bmp", "tiff", "webp"];
+        extensions.extend(raw::EXTENSIONS);
+        #[cfg(feature = "heic")]
+        extensions.extend(heic::EXTENSIONS);
+        extensions
     }
 
-    /// Returns true if the image can be processed.
+    /// Returns true if the image can be processed, judging by its extension. Files are recognised
+    /// by their content instead when indexing with `IndexerOptions::by_content`.
     pub fn is_valid(&self) -> bool {
         if let Some(extension) = self.path.extension() {
             let extension_str = extension.to_str().unwrap_or("").to_lowercase();
@@ -50,6 +86,13 @@ impl Image {
         }
     }
 
+    // HEIF files are recognised by their content as well as their extension
+    #[cfg(feature = "heic")]
+    fn is_heif(&self) -> bool {
+        heic::is_heif(&self.path)
+            || FileType::detect(&self.path).is_ok_and(|t| t == Some(FileType::Heif))
+    }
+
     /// Returns true if the image has a sidecar file.
     pub fn has_sidecar(&self) -> bool {
         let sidecar = self.path.with_extension("xmp");
@@ -58,10 +101,21 @@ impl Image {
 
     /// Returns the metadata of the image.
     pub fn metadata(&self) -> Result<rexif::ExifData, AppError> {
+        #[cfg(feature = "heic")]
+        if self.is_heif() {
+            let exif = heic::exif(&self.path)?.ok_or(rexif::ExifError::ExifIfdEntryNotFound)?;
+            return Ok(rexif::parse_buffer_quiet(&exif).0?);
+        }
+
         let metadata = rexif::parse_file(&self.path)?;
         Ok(metadata)
     }
 
+    /// Returns the camera, capture date and location recorded in the metadata.
+    pub fn exif_summary(&self) -> Result<ExifSummary, AppError> {
+        Ok(ExifSummary::from_exif(&self.metadata()?))
+    }
+
     /// Get the orientation of the image.
     pub fn orientation(&self) -> Orientation {
         match self.metadata() {
@@ -91,38 +145,60 @@ impl Image {
         }
     }
 
-    /// Returns the unrotated image.
+    /// Returns the image as it is meant to be displayed, with its orientation applied.
     pub fn image(&self) -> Result<DynamicImage, AppError> {
-        let mut image = image::open(&self.path)?;
-
-        match self.orientation() {
-            Orientation::Rotated180 => {
-                debug!("rotated 180 degrees");
-                image = image.rotate180();
-            }
-            Orientation::Rotated90 => {
-                debug!("rotated 90 degrees clockwise");
-                image = image.rotate90();
-            }
-            Orientation::Rotated270 => {
-                debug!("rotated 90 degrees counterclockwise");
-                image = image.rotate270();
-            }
-            // todo: complete other rotation variants
-            _ => {}
+        // Decoded upright, whatever the EXIF orientation says
+        #[cfg(feature = "heic")]
+        if self.is_heif() {
+            return heic::decode(&self.path);
         }
 
-        Ok(image)
+        // RAW files are represented by the preview rendered by the camera
+        let image = if raw::is_raw(&self.path) {
+            raw::decode_preview(&self.path)?
+        } else {
+            // The format comes from the content, so misnamed files still decode
+            ImageReader::open(&self.path)?
+                .with_guessed_format()?
+                .decode()?
+        };
+        let orientation = self.orientation();
+        debug!("orientation {:?}", orientation);
+
+        Ok(match orientation {
+            Orientation::Normal | Orientation::Unknown => image,
+            Orientation::MirrorHorizontal => image.fliph(),
+            Orientation::Rotated180 => image.rotate180(),
+            Orientation::MirrorVertical => image.flipv(),
+            // Mirrored along the top-left to bottom-right diagonal
+            Orientation::MirroredHorizontallyAndRotated270 => image.rotate90().fliph(),
+            Orientation::Rotated90 => image.rotate90(),
+            // Mirrored along the top-right to bottom-left diagonal
+            Orientation::MirroredHorizontallyAndRotated90 => image.rotate270().fliph(),
+            Orientation::Rotated270 => image.rotate270(),
+        })
     }
 
-    /// Returns the resolution of the image.
+    /// Returns the resolution of the image with its orientation applied. For RAW files this is the
+    /// resolution of the embedded preview.
     pub fn resolution(&self) -> Result<(u32, u32), AppError> {
-        let reader = ImageReader::open(&self.path)?;
-        let (w, h) = reader.into_dimensions()?;
+        #[cfg(feature = "heic")]
+        if self.is_heif() {
+            return heic::dimensions(&self.path);
+        }
 
-        match self.orientation() {
-            Orientation::Rotated90 | Orientation::Rotated270 => Ok((h, w)),
-            _ => Ok((w, h)),
+        let (w, h) = if raw::is_raw(&self.path) {
+            raw::preview_dimensions(&self.path)?
+        } else {
+            ImageReader::open(&self.path)?
+                .with_guessed_format()?
+                .into_dimensions()?
+        };
+
+        if self.orientation().swaps_dimensions() {
+            Ok((h, w))
+        } else {
+            Ok((w, h))
         }
     }
 
@@ -133,52 +209,87 @@ impl Image {
     }
 
     /// Return the hash of the image.
-    pub fn hash(&self, chunksize: f32) -> Result<u64, AppError> {
-        if chunksize <= 0.0 || chunksize > 1.0 {
-            return Err(AppError::InvalidHashChunkSize(chunksize));
-        }
+    pub fn hash(&self, mode: HashMode) -> Result<u64, AppError> {
+        hash_pixels(&self.image()?, mode)
+    }
 
-        // Convert the image to RGB8 format
-        let rgb_image = self.image()?.to_rgb8();
-        let (width, height) = rgb_image.dimensions();
-
-        // Define the chunk size (e.g., 10% of the image)
-        let chunk_width = ((width as f32) * chunksize) as u32;
-        let chunk_height = ((height as f32) * chunksize) as u32;
-
-        // Collect the pixel data from the chunk
-        let mut chunk_data = Vec::with_capacity((chunk_width * chunk_height * 3) as usize);
-        for y in 0..chunk_height {
-            for x in 0..chunk_width {
-                let pixel = rgb_image.get_pixel(x, y);
-                chunk_data.extend_from_slice(&pixel.0);
-            }
-        }
+    /// Returns the perceptual hash of the image computed with `algorithm`.
+    pub fn perceptual_hash(&self, algorithm: HashAlgorithm) -> Result<PerceptualHash, AppError> {
+        Ok(fingerprint::perceptual_hash(&self.image()?, algorithm))
+    }
 
-        // Create a hash of the chunk data using xxHash
-        let hash = xxh3_64(&chunk_data);
+    /// Returns the average, difference and DCT hashes of the image.
+    pub fn fingerprints(&self) -> Result<Fingerprints, AppError> {
+        Ok(Fingerprints::from_image(&self.image()?))
+    }
 
-        Ok(hash)
+    /// Returns the Hamming distance between the perceptual hashes of two images.
+    /// Small distances indicate near-duplicates.
+    pub fn hash_distance(&self, other: &Image, algorithm: HashAlgorithm) -> Result<u32, AppError> {
+        let self_hash = self.perceptual_hash(algorithm)?;
+        let other_hash = other.perceptual_hash(algorithm)?;
+        Ok(self_hash.distance(&other_hash))
     }
 }
 
 impl PartialEq for Image {
     /// Returns true if the image hahes are equal.
     fn eq(&self, other: &Self) -> bool {
-        const CHUNKSIZE: f32 = 0.1;
-        match (self.hash(CHUNKSIZE), other.hash(CHUNKSIZE)) {
+        const MODE: HashMode = HashMode::Chunk(0.1);
+        match (self.hash(MODE), other.hash(MODE)) {
             (Ok(self_hash), Ok(other_hash)) => self_hash == other_hash,
             _ => false,
         }
     }
 }
 
+/// Hash the pixels of a decoded image.
+pub fn hash_pixels(img: &DynamicImage, mode: HashMode) -> Result<u64, AppError> {
+    // Convert the image to RGB8 format
+    let rgb_image = img.to_rgb8();
+    let (width, height) = rgb_image.dimensions();
+
+    let chunksize = match mode {
+        HashMode::Chunk(chunksize) if chunksize <= 0.0 || chunksize > 1.0 => {
+            return Err(AppError::InvalidHashChunkSize(chunksize));
+        }
+        HashMode::Chunk(chunksize) => chunksize,
+        HashMode::Full => {
+            // Include the dimensions so that a transposed buffer can't collide
+            let mut data = Vec::with_capacity(rgb_image.as_raw().len() + 8);
+            data.extend_from_slice(&width.to_le_bytes());
+            data.extend_from_slice(&height.to_le_bytes());
+            data.extend_from_slice(rgb_image.as_raw());
+            return Ok(xxh3_64(&data));
+        }
+    };
+
+    // Define the chunk size (e.g., 10% of the image)
+    let chunk_width = ((width as f32) * chunksize) as u32;
+    let chunk_height = ((height as f32) * chunksize) as u32;
+
+    // Collect the pixel data from the chunk
+    let mut chunk_data = Vec::with_capacity((chunk_width * chunk_height * 3) as usize);
+    for y in 0..chunk_height {
+        for x in 0..chunk_width {
+            let pixel = rgb_image.get_pixel(x, y);
+            chunk_data.extend_from_slice(&pixel.0);
+        }
+    }
+
+    // Create a hash of the chunk data using xxHash
+    let hash = xxh3_64(&chunk_data);
+
+    Ok(hash)
+}
+
 // tests ------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use tempfile::TempDir;
 
     #[test]
     fn test_from_path() {
@@ -193,6 +304,31 @@ mod tests {
         assert!(img.is_valid());
     }
 
+    #[test]
+    fn test_misnamed_image() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+
+        // A PNG saved with a JPEG extension, and a JPEG backup
+        let original = get_img("orientation/orientation-1.jpg").unwrap();
+        let png = dir.join("IMG_0001.jpg");
+        original
+            .image()
+            .unwrap()
+            .save_with_format(&png, image::ImageFormat::Png)
+            .unwrap();
+        let backup = dir.join("IMG_0001.JPG.bak");
+        std::fs::copy(&original.path, &backup).unwrap();
+
+        // Decoded by their content, although only the first has an image extension
+        for path in [&png, &backup] {
+            let img = Image::from_path(path).unwrap();
+            assert_eq!(img.is_valid(), path == &png);
+            assert_eq!(img.resolution().unwrap(), (96, 64));
+            assert!(img.image().is_ok());
+        }
+    }
+
     #[test]
     fn test_has_sidecar() {
         let img = get_img("01/house.jpg").unwrap();
@@ -215,6 +351,46 @@ mod tests {
         assert!(matches!(img_90deg.orientation(), Orientation::Rotated90));
     }
 
+    #[test]
+    fn test_all_orientations() {
+        let expected = [
+            Orientation::Normal,
+            Orientation::MirrorHorizontal,
+            Orientation::Rotated180,
+            Orientation::MirrorVertical,
+            Orientation::MirroredHorizontallyAndRotated270,
+            Orientation::Rotated90,
+            Orientation::MirroredHorizontallyAndRotated90,
+            Orientation::Rotated270,
+        ];
+        let upright = get_img("orientation/orientation-1.jpg")
+            .unwrap()
+            .image()
+            .unwrap()
+            .to_rgb8();
+
+        // Each fixture stores the same picture so that it is upright once its orientation is
+        // applied: a red block at the top left and a blue bar along the bottom
+        for (i, orientation) in expected.into_iter().enumerate() {
+            let img = get_img(&format!("orientation/orientation-{}.jpg", i + 1)).unwrap();
+            assert_eq!(img.orientation(), orientation);
+            assert_eq!(img.resolution().unwrap(), (96, 64), "{:?}", orientation);
+
+            let pixels = img.image().unwrap().to_rgb8();
+            assert_eq!(pixels.dimensions(), (96, 64));
+            assert!(pixels.get_pixel(4, 4)[0] > 180, "{:?}", orientation);
+            assert!(pixels.get_pixel(90, 60)[2] > 180, "{:?}", orientation);
+
+            let difference: u64 = pixels
+                .as_raw()
+                .iter()
+                .zip(upright.as_raw())
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum();
+            assert!(difference / (pixels.len() as u64) < 4, "{:?}", orientation);
+        }
+    }
+
     #[test]
     // #[ignore = "slow"]
     fn test_resolution() {
@@ -236,8 +412,41 @@ mod tests {
         let img2 = get_img("01/house-duplicate.jpg").unwrap();
         let img3 = get_img("01/coffee.jpeg").unwrap();
 
-        assert_eq!(img1.hash(0.1).unwrap(), img2.hash(0.1).unwrap());
-        assert_ne!(img1.hash(0.1).unwrap(), img3.hash(0.1).unwrap())
+        assert_eq!(
+            img1.hash(HashMode::Chunk(0.1)).unwrap(),
+            img2.hash(HashMode::Chunk(0.1)).unwrap()
+        );
+        assert_ne!(
+            img1.hash(HashMode::Chunk(0.1)).unwrap(),
+            img3.hash(HashMode::Chunk(0.1)).unwrap()
+        )
+    }
+
+    #[test]
+    fn test_hash_full() {
+        // The files differ in their metadata only
+        let img1 = get_img("01/house.jpg").unwrap();
+        let img2 = get_img("01/house-duplicate.jpg").unwrap();
+        assert_eq!(
+            img1.hash(HashMode::Full).unwrap(),
+            img2.hash(HashMode::Full).unwrap()
+        );
+
+        // A different image
+        let img3 = get_img("02/face-right-1-small.jpg").unwrap();
+        assert_ne!(
+            img1.hash(HashMode::Full).unwrap(),
+            img3.hash(HashMode::Full).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hash_invalid_chunk_size() {
+        let img = get_img("02/face-right-1-small.jpg").unwrap();
+        assert!(matches!(
+            img.hash(HashMode::Chunk(1.5)),
+            Err(AppError::InvalidHashChunkSize(_))
+        ));
     }
 
     #[test]
@@ -251,6 +460,29 @@ mod tests {
         assert_ne!(img1, img3);
     }
 
+    #[test]
+    #[ignore = "slow"]
+    fn test_hash_distance() {
+        let img1 = get_img("02/face-right-1.jpg").unwrap();
+        let img2 = get_img("02/face-right-1-small.jpg").unwrap();
+        let img3 = get_img("02/face-left.jpg").unwrap();
+
+        assert!(img1.hash_distance(&img2, HashAlgorithm::Dct).unwrap() <= 4);
+        assert!(img1.hash_distance(&img3, HashAlgorithm::Dct).unwrap() > 10);
+    }
+
+    #[test]
+    fn test_fingerprints_rotated() {
+        // The second image is stored rotated
+        let img1 = get_img("02/face-right-1.jpg").unwrap();
+        let img2 = get_img("02/face-right-2.jpg").unwrap();
+
+        let fp1 = img1.fingerprints().unwrap();
+        let fp2 = img2.fingerprints().unwrap();
+
+        assert!(fp1.difference.distance(&fp2.difference) <= 6);
+    }
+
     fn get_img(img_name: &str) -> Result<Image, AppError> {
         let test_dir = PathBuf::from("test-data");
         let img_path = test_dir.join(img_name);
@@ -1,34 +1,689 @@
-//! Recursively index a directory and generate a list of image paths.
+//! Recursively index a directory and generate a list of image or video paths.
+//! `IndexerOptions` narrows the walk down with patterns, ignore files and limits on depth and size,
+//! e.g. to leave out Synology `@eaDir` folders or Lightroom previews.
 
-use std::path::PathBuf;
-use walkdir::WalkDir;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use log::warn;
+use rayon::prelude::*;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::error::AppError;
+use crate::filetype::FileType;
+use crate::glob::{last_match, read_ignore_file, Glob};
 use crate::image::Image;
+use crate::progress::{NoProgress, Phase, Progress};
+use crate::raw;
+use crate::video;
+
+/// Formats a camera writes next to its RAW files, most preferred first.
+const RENDERED_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "heic", "heif", "tif", "tiff"];
+
+/// A RAW file and the image the camera rendered from it, e.g. `DSC_0001.NEF` and `DSC_0001.JPG`.
+/// The two are a single shot rather than duplicates of each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawPair {
+    pub rendered: PathBuf,
+    pub raw: PathBuf,
+}
+
+/// How symbolic links are treated while indexing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Leave out links to files and folders.
+    Skip,
+    /// Index links to files without descending into linked folders.
+    #[default]
+    Files,
+    /// Follow links to files and folders. Links looping back to a parent are skipped.
+    Follow,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "files" => Ok(SymlinkPolicy::Files),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            _ => Err(AppError::UnknownSymlinkPolicy(s.to_string())),
+        }
+    }
+}
+
+/// Which files are indexed, built by chaining calls on `IndexerOptions::new()`. By default every
+/// file with a supported extension is indexed.
+#[derive(Debug, Clone, Default)]
+pub struct IndexerOptions {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    ignore_files: Vec<String>,
+    skip_hidden: bool,
+    max_depth: Option<usize>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    symlinks: SymlinkPolicy,
+    by_content: bool,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl IndexerOptions {
+    pub fn new() -> IndexerOptions {
+        IndexerOptions::default()
+    }
+
+    /// Only index files matching one of the include patterns, relative to the scanned folder.
+    pub fn include(mut self, pattern: &str) -> Result<IndexerOptions, AppError> {
+        self.include.push(Glob::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Leave out files and folders matching the pattern, relative to the scanned folder.
+    pub fn exclude(mut self, pattern: &str) -> Result<IndexerOptions, AppError> {
+        self.exclude.push(Glob::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Read `.gitignore`-style patterns from files with this name, each applying to the folder it
+    /// is in and below.
+    pub fn ignore_file(mut self, name: &str) -> IndexerOptions {
+        self.ignore_files.push(name.to_string());
+        self
+    }
+
+    /// Leave out files and folders whose name starts with a dot.
+    pub fn skip_hidden(mut self, skip: bool) -> IndexerOptions {
+        self.skip_hidden = skip;
+        self
+    }
+
+    /// Descend at most `depth` folders below the scanned folder, 1 indexing only its own files.
+    pub fn max_depth(mut self, depth: usize) -> IndexerOptions {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Leave out files smaller than `bytes`.
+    pub fn min_size(mut self, bytes: u64) -> IndexerOptions {
+        self.min_size = Some(bytes);
+        self
+    }
+
+    /// Leave out files larger than `bytes`.
+    pub fn max_size(mut self, bytes: u64) -> IndexerOptions {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> IndexerOptions {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Recognise files by their content whatever their extension, so that backups such as
+    /// `IMG_0001.JPG.bak` and files without an extension are included. Files whose extension
+    /// doesn't match their content are logged.
+    pub fn by_content(mut self, by_content: bool) -> IndexerOptions {
+        self.by_content = by_content;
+        self
+    }
+
+    /// Report each file examined to `progress`, as part of the indexing phase.
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> IndexerOptions {
+        self.progress = Some(progress);
+        self
+    }
+}
 
 pub fn index_images_in_folder(folder: PathBuf) -> Vec<PathBuf> {
-    let mut image_files = Vec::new();
-    let valid_extensions = Image::valid_extensions();
+    index_images(folder, &IndexerOptions::default())
+}
+
+pub fn index_videos_in_folder(folder: PathBuf) -> Vec<PathBuf> {
+    index_videos(folder, &IndexerOptions::default())
+}
+
+pub fn index_images(folder: PathBuf, options: &IndexerOptions) -> Vec<PathBuf> {
+    index_folder(folder, options)
+        .into_iter()
+        .filter_map(|(path, media)| (media == Media::Image).then_some(path))
+        .collect()
+}
+
+pub fn index_videos(folder: PathBuf, options: &IndexerOptions) -> Vec<PathBuf> {
+    index_folder(folder, options)
+        .into_iter()
+        .filter_map(|(path, media)| (media == Media::Video).then_some(path))
+        .collect()
+}
+
+/// Index the images and videos of several folders, walking each once and in parallel. Folders
+/// inside another root are left out, as with `distinct_roots`. Returns the images and the videos,
+/// in the order of their roots.
+pub fn index_media(roots: &[PathBuf], options: &IndexerOptions) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let progress = options.reporter();
+    progress.start(Phase::Indexing, None);
+    let indexed: Vec<Vec<(PathBuf, Media)>> = distinct_roots(roots)
+        .into_par_iter()
+        .map(|root| index_folder(root, options))
+        .collect();
+    progress.finish(Phase::Indexing);
+
+    let mut images = Vec::new();
+    let mut videos = Vec::new();
+    for (path, media) in indexed.into_iter().flatten() {
+        match media {
+            Media::Image => images.push(path),
+            Media::Video => videos.push(path),
+        }
+    }
+
+    (images, videos)
+}
+
+/// Returns the roots to walk when scanning several folders, leaving out repeated folders and those
+/// inside another root, whose files would otherwise be indexed twice.
+pub fn distinct_roots(roots: &[PathBuf]) -> Vec<PathBuf> {
+    let canonical: Vec<PathBuf> = roots
+        .iter()
+        .map(|root| fs::canonicalize(root).unwrap_or_else(|_| root.clone()))
+        .collect();
+
+    let mut distinct = Vec::new();
+    for (i, root) in roots.iter().enumerate() {
+        let covered = canonical.iter().enumerate().any(|(j, other)| {
+            j != i && canonical[i].starts_with(other) && (canonical[i] != *other || j < i)
+        });
+        if covered {
+            warn!(
+                "{} is already scanned as part of another root",
+                root.display()
+            );
+        } else {
+            distinct.push(root.clone());
+        }
+    }
+
+    distinct
+}
+
+/// Find the RAW files with a rendered image of the same name in the same folder. A RAW file with
+/// several rendered images is paired with the one of the most preferred format.
+pub fn find_raw_pairs(paths: &[PathBuf]) -> Vec<RawPair> {
+    // Rendered images by folder and stem, with the rank of their format
+    let mut rendered: HashMap<(PathBuf, String), (usize, &PathBuf)> = HashMap::new();
+    for path in paths {
+        let extension = lowercase_extension(path);
+        let Some(rank) = RENDERED_EXTENSIONS.iter().position(|e| *e == extension) else {
+            continue;
+        };
+        let best = rendered.entry(shot(path)).or_insert((rank, path));
+        if rank < best.0 {
+            *best = (rank, path);
+        }
+    }
+
+    let mut pairs: Vec<RawPair> = paths
+        .iter()
+        .filter(|path| raw::is_raw(path))
+        .filter_map(|path| {
+            rendered.get(&shot(path)).map(|(_, image)| RawPair {
+                rendered: (*image).clone(),
+                raw: path.clone(),
+            })
+        })
+        .collect();
+    pairs.sort_by(|a, b| a.raw.cmp(&b.raw));
+
+    pairs
+}
+
+// Whether a file is indexed as an image or a video
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Media {
+    Image,
+    Video,
+}
+
+fn index_folder(folder: PathBuf, options: &IndexerOptions) -> Vec<(PathBuf, Media)> {
+    // Sorted so that the files are listed in the same order on every run
+    let mut walker = WalkDir::new(&folder)
+        .follow_links(options.symlinks == SymlinkPolicy::Follow)
+        .sort_by_file_name();
+    if let Some(depth) = options.max_depth {
+        walker = walker.max_depth(depth);
+    }
+
+    // The patterns of the ignore files in the folders above the current entry, with the depth
+    // and path of their folder
+    let mut ignored: Vec<(usize, PathBuf, Vec<Glob>)> = Vec::new();
+
+    let entries = walker.into_iter().filter_entry(|entry| {
+        let depth = entry.depth();
+        while ignored.last().is_some_and(|(d, _, _)| *d >= depth) {
+            ignored.pop();
+        }
+
+        if depth > 0 && !options.admits(entry, &folder, &ignored) {
+            return false;
+        }
+        if entry.file_type().is_dir() {
+            let globs = read_ignore_files(entry.path(), &options.ignore_files);
+            if !globs.is_empty() {
+                ignored.push((depth, entry.path().to_path_buf(), globs));
+            }
+        }
+        true
+    });
+
+    let progress = options.reporter();
+    let paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|entry| !entry.file_type().is_dir())
+        .inspect(|_| progress.advance(Phase::Indexing, 1))
+        .map(|entry| entry.into_path())
+        .collect();
+
+    // Checking a file may mean reading its metadata or first bytes, so do it in parallel
+    let image_extensions = Image::valid_extensions();
+    paths
+        .into_par_iter()
+        .filter(|path| path.is_file() && options.admits_file(path, &folder))
+        .filter_map(|path| {
+            let media = options.media(&path, &image_extensions)?;
+            Some((path, media))
+        })
+        .collect()
+}
+
+impl IndexerOptions {
+    // Whether a file or folder below the scanned folder passes the folder-level filters
+    fn admits(
+        &self,
+        entry: &DirEntry,
+        folder: &Path,
+        ignored: &[(usize, PathBuf, Vec<Glob>)],
+    ) -> bool {
+        if self.symlinks == SymlinkPolicy::Skip && entry.path_is_symlink() {
+            return false;
+        }
+        if self.skip_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            return false;
+        }
 
-    for entry in WalkDir::new(folder).into_iter().filter_map(|e| e.ok()) {
         let path = entry.path();
-        let extension = path.extension().unwrap_or_default().to_str().unwrap();
-        if path.is_file() && valid_extensions.contains(&extension) {
-            image_files.push(path.to_path_buf());
+        let is_dir = entry.file_type().is_dir();
+        let relative = path.strip_prefix(folder).unwrap_or(path);
+        if self
+            .exclude
+            .iter()
+            .any(|glob| glob.matches(relative, is_dir))
+        {
+            return false;
+        }
+
+        // Patterns in deeper folders take precedence
+        let mut excluded = false;
+        for (_, dir, globs) in ignored {
+            let relative = path.strip_prefix(dir).unwrap_or(path);
+            if let Some(glob) = last_match(globs, relative, is_dir) {
+                excluded = !glob.negated;
+            }
+        }
+        !excluded
+    }
+
+    // Whether a file is indexed as an image, a video or not at all
+    fn media(&self, path: &Path, image_extensions: &[&str]) -> Option<Media> {
+        if self.by_content {
+            return match detect(path)? {
+                FileType::Video => Some(Media::Video),
+                file_type => file_type.is_image().then_some(Media::Image),
+            };
+        }
+
+        let extension = lowercase_extension(path);
+        if image_extensions.contains(&extension.as_str()) {
+            Some(Media::Image)
+        } else if video::EXTENSIONS.contains(&extension.as_str()) {
+            Some(Media::Video)
+        } else {
+            None
         }
     }
 
-    image_files
+    fn reporter(&self) -> &dyn Progress {
+        self.progress.as_deref().unwrap_or(&NoProgress)
+    }
+
+    // Whether a file passes the include patterns and size limits
+    fn admits_file(&self, path: &Path, folder: &Path) -> bool {
+        let relative = path.strip_prefix(folder).unwrap_or(path);
+        if !self.include.is_empty()
+            && !self
+                .include
+                .iter()
+                .any(|glob| glob.matches(relative, false))
+        {
+            return false;
+        }
+
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let Ok(size) = fs::metadata(path).map(|metadata| metadata.len()) else {
+                return false;
+            };
+            if self.min_size.is_some_and(|min| size < min)
+                || self.max_size.is_some_and(|max| size > max)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// The patterns of the ignore files in a folder
+fn read_ignore_files(dir: &Path, names: &[String]) -> Vec<Glob> {
+    let mut globs = Vec::new();
+    for name in names {
+        let path = dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        match read_ignore_file(&path) {
+            Ok(patterns) => globs.extend(patterns),
+            Err(e) => warn!("Ignoring {}: {}", path.display(), e),
+        }
+    }
+    globs
+}
+
+// Cameras and phones often write upper case extensions such as IMG_0001.HEIC
+fn lowercase_extension(path: &Path) -> String {
+    path.extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
+
+// The type of a file from its content, warning when its extension says otherwise
+fn detect(path: &Path) -> Option<FileType> {
+    let file_type = FileType::detect(path).ok()??;
+    if !file_type.has_extension(&lowercase_extension(path)) {
+        warn!(
+            "{} is a {} file despite its extension",
+            path.display(),
+            file_type
+        );
+    }
+
+    Some(file_type)
+}
+
+// The folder and file name without extension
+fn shot(path: &Path) -> (PathBuf, String) {
+    (
+        path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::progress::ProgressFn;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tempfile::TempDir;
 
     #[test]
     fn test_index_images_in_folder() {
         let test_dir = PathBuf::from("test-data");
         let image_files = index_images_in_folder(test_dir);
 
-        assert_eq!(image_files.len(), 11);
+        // magazine.heic is only indexed when HEIC support is enabled
+        let expected = if cfg!(feature = "heic") { 21 } else { 20 };
+        assert_eq!(image_files.len(), expected);
+    }
+
+    #[test]
+    fn test_index_videos_in_folder() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        for name in ["IMG_0001.MOV", "clip.mp4", "photo.jpg", "notes.txt"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let mut videos = index_videos_in_folder(dir.to_path_buf());
+        videos.sort();
+        assert_eq!(videos, vec![dir.join("IMG_0001.MOV"), dir.join("clip.mp4")]);
+        assert_eq!(
+            index_images_in_folder(dir.to_path_buf()),
+            vec![dir.join("photo.jpg")]
+        );
+    }
+
+    #[test]
+    fn test_index_by_content() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let jpeg = std::fs::read("test-data/orientation/orientation-1.jpg").unwrap();
+        for name in ["IMG_0001.JPG", "IMG_0001.JPG.bak", "export"] {
+            std::fs::write(dir.join(name), &jpeg).unwrap();
+        }
+        std::fs::write(dir.join("clip.dat"), b"\0\0\0\x14ftypqt  \0\0\0\0qt  ").unwrap();
+        std::fs::write(dir.join("notes.jpg"), b"not an image").unwrap();
+
+        let options = IndexerOptions::new().by_content(true);
+        let mut images = index_images(dir.to_path_buf(), &options);
+        images.sort();
+        assert_eq!(
+            images,
+            vec![
+                dir.join("IMG_0001.JPG"),
+                dir.join("IMG_0001.JPG.bak"),
+                dir.join("export")
+            ]
+        );
+        assert_eq!(
+            index_videos(dir.to_path_buf(), &options),
+            vec![dir.join("clip.dat")]
+        );
+    }
+
+    #[test]
+    fn test_indexer_options() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let jpeg = std::fs::read("test-data/orientation/orientation-1.jpg").unwrap();
+        for path in [
+            "a.jpg",
+            ".hidden.jpg",
+            "2023/b.jpg",
+            "2023/@eaDir/b.jpg",
+            "2023/holiday/c.jpg",
+            "2023/holiday/d.png",
+            "2023/holiday/private/e.jpg",
+            "Catalog Previews.lrdata/f.jpg",
+        ] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, &jpeg).unwrap();
+        }
+        std::fs::write(dir.join("small.jpg"), &jpeg[..100]).unwrap();
+        std::fs::write(
+            dir.join("2023/.deduperignore"),
+            "# Not shared\nholiday/*\n!*.jpg\nprivate/\n",
+        )
+        .unwrap();
+
+        let index = |options: IndexerOptions| {
+            let mut paths: Vec<String> = index_images(dir.to_path_buf(), &options)
+                .iter()
+                .map(|path| {
+                    path.strip_prefix(dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(index(IndexerOptions::new()).len(), 9);
+        assert_eq!(
+            index(
+                IndexerOptions::new()
+                    .exclude("@eaDir/")
+                    .unwrap()
+                    .exclude("*.lrdata/")
+                    .unwrap()
+                    .ignore_file(".deduperignore")
+                    .skip_hidden(true)
+                    .min_size(1000)
+            ),
+            vec!["2023/b.jpg", "2023/holiday/c.jpg", "a.jpg"]
+        );
+        assert_eq!(
+            index(
+                IndexerOptions::new()
+                    .include("2023/**")
+                    .unwrap()
+                    .max_depth(2)
+            ),
+            vec!["2023/b.jpg"]
+        );
+        assert_eq!(
+            index(IndexerOptions::new().max_size(1000)),
+            vec!["small.jpg"]
+        );
+        assert!(IndexerOptions::new().include("[").is_err());
+        assert!("sometimes".parse::<SymlinkPolicy>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("photos")).unwrap();
+        std::fs::copy(
+            "test-data/orientation/orientation-1.jpg",
+            dir.join("photos/a.jpg"),
+        )
+        .unwrap();
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(dir.join("photos/a.jpg"), root.join("link.jpg")).unwrap();
+        std::os::unix::fs::symlink(dir.join("photos"), root.join("linked")).unwrap();
+        // A loop back to the root is skipped
+        std::os::unix::fs::symlink(&root, root.join("loop")).unwrap();
+
+        let index = |policy: &str| {
+            let options = IndexerOptions::new().symlinks(policy.parse().unwrap());
+            let mut paths = index_images(root.clone(), &options);
+            paths.sort();
+            paths
+        };
+
+        assert!(index("skip").is_empty());
+        assert_eq!(index("files"), vec![root.join("link.jpg")]);
+        assert_eq!(
+            index("follow"),
+            vec![root.join("link.jpg"), root.join("linked/a.jpg")]
+        );
+    }
+
+    #[test]
+    fn test_distinct_roots() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        for folder in ["ssd/photos/2023", "nas"] {
+            std::fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+
+        let roots = [
+            dir.join("ssd/photos/2023"),
+            dir.join("nas"),
+            dir.join("ssd/photos"),
+            dir.join("nas/../nas"),
+        ];
+        assert_eq!(
+            distinct_roots(&roots),
+            vec![dir.join("nas"), dir.join("ssd/photos")]
+        );
+    }
+
+    #[test]
+    fn test_index_media() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        for folder in ["ssd/2023", "nas"] {
+            std::fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        for path in [
+            "ssd/2023/a.jpg",
+            "ssd/2023/b.mov",
+            "nas/c.png",
+            "nas/notes.txt",
+        ] {
+            std::fs::write(dir.join(path), b"").unwrap();
+        }
+
+        let examined = Arc::new(AtomicU64::new(0));
+        let counter = examined.clone();
+        let options =
+            IndexerOptions::new().progress(Arc::new(ProgressFn::new(move |_, done, _| {
+                counter.store(done, Ordering::Relaxed)
+            })));
+
+        let roots = [dir.join("nas"), dir.join("ssd"), dir.join("ssd/2023")];
+        let (images, videos) = index_media(&roots, &options);
+        assert_eq!(
+            images,
+            vec![dir.join("nas/c.png"), dir.join("ssd/2023/a.jpg")]
+        );
+        assert_eq!(videos, vec![dir.join("ssd/2023/b.mov")]);
+        assert_eq!(examined.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_find_raw_pairs() {
+        let paths: Vec<PathBuf> = [
+            "a/DSC_0001.NEF",
+            "a/DSC_0001.JPG",
+            "a/DSC_0001.tiff",
+            "a/DSC_0002.NEF",
+            "b/DSC_0002.jpg",
+            "b/DSC_0003.dng",
+            "b/DSC_0003.heic",
+            "b/DSC_0004.jpg",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        assert_eq!(
+            find_raw_pairs(&paths),
+            vec![
+                RawPair {
+                    rendered: PathBuf::from("a/DSC_0001.JPG"),
+                    raw: PathBuf::from("a/DSC_0001.NEF"),
+                },
+                RawPair {
+                    rendered: PathBuf::from("b/DSC_0003.heic"),
+                    raw: PathBuf::from("b/DSC_0003.dng"),
+                },
+            ]
+        );
     }
 }
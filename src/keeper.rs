@@ -0,0 +1,328 @@
+//! Keeper selection.
+//! Ranks the files of a duplicate group so that the best copy is kept. Rules are applied in
+//! order, each one only breaking the ties left by the rules before it.
+
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error::AppError;
+use crate::raw;
+use crate::report::ReportedFile;
+use crate::video;
+
+/// A criterion for preferring one copy of an image over another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeeperRule {
+    /// More pixels.
+    Resolution,
+    /// Larger file, usually less compressed.
+    FileSize,
+    /// Earlier capture date, so the original beats later exports. Files without one come last.
+    ExifDate,
+    /// Has a RAW file from the same shot.
+    RawPair,
+    /// Has a motion component, a Live Photo video or a video embedded in a motion photo.
+    Motion,
+    /// Lies under the policy's primary root.
+    PrimaryRoot,
+    /// Has an XMP sidecar holding edits or keywords.
+    Sidecar,
+    /// Lies under an earlier entry of the policy's preferred paths.
+    PreferredPath,
+}
+
+impl FromStr for KeeperRule {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "resolution" => Ok(KeeperRule::Resolution),
+            "size" => Ok(KeeperRule::FileSize),
+            "date" => Ok(KeeperRule::ExifDate),
+            "raw" => Ok(KeeperRule::RawPair),
+            "motion" => Ok(KeeperRule::Motion),
+            "primary" => Ok(KeeperRule::PrimaryRoot),
+            "sidecar" => Ok(KeeperRule::Sidecar),
+            "path" => Ok(KeeperRule::PreferredPath),
+            _ => Err(AppError::UnknownKeeperRule(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeeperPolicy {
+    pub rules: Vec<KeeperRule>,
+    /// Folders whose files are preferred, most preferred first.
+    pub preferred_paths: Vec<PathBuf>,
+    /// The scanned root whose copies survive, e.g. the main photo library when also scanning
+    /// backups. Files are matched by the root they are tagged with rather than by their path, so
+    /// this has to be spelled as one of `ScanOptions::roots`.
+    pub primary_root: Option<PathBuf>,
+}
+
+impl Default for KeeperPolicy {
+    fn default() -> Self {
+        KeeperPolicy {
+            // Without a primary root every file ties on it
+            rules: vec![
+                KeeperRule::PrimaryRoot,
+                KeeperRule::Resolution,
+                KeeperRule::FileSize,
+                KeeperRule::ExifDate,
+                KeeperRule::RawPair,
+                KeeperRule::Motion,
+                KeeperRule::Sidecar,
+                KeeperRule::PreferredPath,
+            ],
+            preferred_paths: Vec::new(),
+            primary_root: None,
+        }
+    }
+}
+
+impl KeeperPolicy {
+    /// Compare two files, `Ordering::Less` meaning `a` is the better keeper.
+    /// Files that tie on every rule are ordered by path so that the ranking is deterministic.
+    pub fn compare(&self, a: &ReportedFile, b: &ReportedFile) -> Ordering {
+        self.rules
+            .iter()
+            .map(|rule| self.compare_by(*rule, a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.path.cmp(&b.path))
+    }
+
+    /// Sort files from best to worst keeper.
+    pub fn rank(&self, files: &mut [ReportedFile]) {
+        files.sort_by(|a, b| self.compare(a, b));
+    }
+
+    fn compare_by(&self, rule: KeeperRule, a: &ReportedFile, b: &ReportedFile) -> Ordering {
+        match rule {
+            KeeperRule::Resolution => pixels(b).cmp(&pixels(a)),
+            KeeperRule::FileSize => b.size.cmp(&a.size),
+            KeeperRule::ExifDate => match (&a.date_time_original, &b.date_time_original) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            KeeperRule::RawPair => has_raw(b).cmp(&has_raw(a)),
+            KeeperRule::Motion => has_motion(b).cmp(&has_motion(a)),
+            KeeperRule::PrimaryRoot => self.in_primary(b).cmp(&self.in_primary(a)),
+            KeeperRule::Sidecar => b.sidecar.cmp(&a.sidecar),
+            KeeperRule::PreferredPath => self.path_rank(a).cmp(&self.path_rank(b)),
+        }
+    }
+
+    // A root nested in the primary one counts as part of it
+    fn in_primary(&self, file: &ReportedFile) -> bool {
+        match (&self.primary_root, &file.root) {
+            (Some(primary), Some(root)) => root.starts_with(primary),
+            _ => false,
+        }
+    }
+
+    // Index of the first preferred folder containing the file, unlisted folders last
+    fn path_rank(&self, file: &ReportedFile) -> usize {
+        self.preferred_paths
+            .iter()
+            .position(|prefix| file.path.starts_with(prefix))
+            .unwrap_or(self.preferred_paths.len())
+    }
+}
+
+fn pixels(file: &ReportedFile) -> u64 {
+    file.resolution.map_or(0, |(w, h)| w as u64 * h as u64)
+}
+
+fn has_raw(file: &ReportedFile) -> bool {
+    file.companions.iter().any(|path| raw::is_raw(path))
+}
+
+fn has_motion(file: &ReportedFile) -> bool {
+    file.motion || file.companions.iter().any(|path| video::is_video(path))
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_apply_in_order() {
+        let mut files = vec![
+            file("b/small.jpg", (250, 375), 100, None, false),
+            file("b/large.jpg", (2520, 3776), 100, None, false),
+            file("b/large-bigger-file.jpg", (2520, 3776), 200, None, false),
+        ];
+
+        KeeperPolicy::default().rank(&mut files);
+
+        assert_eq!(
+            paths(&files),
+            vec!["b/large-bigger-file.jpg", "b/large.jpg", "b/small.jpg"]
+        );
+    }
+
+    #[test]
+    fn test_date_sidecar_and_path_break_ties() {
+        let policy = KeeperPolicy {
+            preferred_paths: vec![PathBuf::from("primary")],
+            ..KeeperPolicy::default()
+        };
+
+        let undated = file("a/undated.jpg", (4, 3), 10, None, true);
+        let later = file("a/later.jpg", (4, 3), 10, Some("2020:01:02 00:00:00"), true);
+        let earlier = file(
+            "a/earlier.jpg",
+            (4, 3),
+            10,
+            Some("2020:01:01 00:00:00"),
+            false,
+        );
+        let earlier_sidecar = file(
+            "b/earlier.jpg",
+            (4, 3),
+            10,
+            Some("2020:01:01 00:00:00"),
+            true,
+        );
+        let preferred = file(
+            "primary/earlier.jpg",
+            (4, 3),
+            10,
+            Some("2020:01:01 00:00:00"),
+            true,
+        );
+
+        let mut files = vec![undated, later, earlier, earlier_sidecar, preferred];
+        policy.rank(&mut files);
+
+        assert_eq!(
+            paths(&files),
+            vec![
+                "primary/earlier.jpg",
+                "b/earlier.jpg",
+                "a/earlier.jpg",
+                "a/later.jpg",
+                "a/undated.jpg"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let policy = KeeperPolicy {
+            rules: vec!["size".parse().unwrap()],
+            ..KeeperPolicy::default()
+        };
+
+        let mut files = vec![
+            file("a.jpg", (4000, 3000), 10, None, false),
+            file("b.jpg", (400, 300), 20, None, false),
+        ];
+        policy.rank(&mut files);
+
+        assert_eq!(paths(&files), vec!["b.jpg", "a.jpg"]);
+        assert!("colour".parse::<KeeperRule>().is_err());
+    }
+
+    #[test]
+    fn test_raw_pair_beats_sidecar() {
+        let mut paired = file("a/DSC_0001.JPG", (4, 3), 10, None, false);
+        paired.companions = vec![PathBuf::from("a/DSC_0001.NEF")];
+        let mut files = vec![file("b/DSC_0001.JPG", (4, 3), 10, None, true), paired];
+
+        KeeperPolicy::default().rank(&mut files);
+
+        assert_eq!(paths(&files), vec!["a/DSC_0001.JPG", "b/DSC_0001.JPG"]);
+    }
+
+    #[test]
+    fn test_motion_beats_sidecar() {
+        let mut live = file("a/IMG_0001.HEIC", (4, 3), 10, None, false);
+        live.companions = vec![PathBuf::from("a/IMG_0001.MOV")];
+        let mut motion = file("c/PXL_0001.MP.jpg", (4, 3), 10, None, false);
+        motion.motion = true;
+
+        let mut files = vec![file("b/IMG_0001.HEIC", (4, 3), 10, None, true), live];
+        KeeperPolicy::default().rank(&mut files);
+        assert_eq!(paths(&files), vec!["a/IMG_0001.HEIC", "b/IMG_0001.HEIC"]);
+
+        let mut files = vec![file("b/PXL_0001.jpg", (4, 3), 10, None, true), motion];
+        KeeperPolicy::default().rank(&mut files);
+        assert_eq!(paths(&files), vec!["c/PXL_0001.MP.jpg", "b/PXL_0001.jpg"]);
+    }
+
+    #[test]
+    fn test_primary_root() {
+        let policy = KeeperPolicy {
+            primary_root: Some(PathBuf::from("/laptop/photos")),
+            ..KeeperPolicy::default()
+        };
+
+        let mut files = vec![
+            file("/nas/a.jpg", (4, 3), 10, None, true),
+            file("/laptop/photos/a.jpg", (4, 3), 10, None, false),
+            file("/laptop/photos/small.jpg", (2, 1), 10, None, false),
+        ];
+        // Untagged files aren't known to be under the primary root
+        let mut untagged = files.clone();
+        policy.rank(&mut untagged);
+        assert_eq!(paths(&untagged)[0], "/nas/a.jpg");
+
+        for file in &mut files {
+            file.root = Some(file.path.parent().unwrap().to_path_buf());
+        }
+        policy.rank(&mut files);
+        assert_eq!(
+            paths(&files),
+            vec![
+                "/laptop/photos/a.jpg",
+                "/laptop/photos/small.jpg",
+                "/nas/a.jpg"
+            ]
+        );
+
+        // Without a primary root the sidecar decides
+        KeeperPolicy::default().rank(&mut files);
+        assert_eq!(paths(&files)[0], "/nas/a.jpg");
+
+        // The primary copy is kept even when the backup is larger
+        let mut files = vec![
+            file("/nas/b.jpg", (4000, 3000), 8_000_000, None, true),
+            file("/laptop/photos/b.jpg", (2000, 1500), 2_000_000, None, false),
+        ];
+        for file in &mut files {
+            file.root = Some(file.path.parent().unwrap().to_path_buf());
+        }
+        policy.rank(&mut files);
+        assert_eq!(paths(&files), vec!["/laptop/photos/b.jpg", "/nas/b.jpg"]);
+    }
+
+    fn file(
+        path: &str,
+        resolution: (u32, u32),
+        size: u64,
+        date: Option<&str>,
+        sidecar: bool,
+    ) -> ReportedFile {
+        ReportedFile {
+            path: PathBuf::from(path),
+            size,
+            resolution: Some(resolution),
+            score: None,
+            date_time_original: date.map(str::to_string),
+            sidecar,
+            companions: Vec::new(),
+            motion: false,
+            root: None,
+        }
+    }
+
+    fn paths(files: &[ReportedFile]) -> Vec<&str> {
+        files.iter().map(|f| f.path.to_str().unwrap()).collect()
+    }
+}
@@ -1,8 +1,28 @@
+pub mod apply;
+pub mod cache;
+pub mod cancel;
+pub mod candidates;
 pub mod duplicates;
-mod error;
+pub mod error;
+pub mod exact;
+pub mod exif;
+pub mod filetype;
+pub mod fingerprint;
+pub mod glob;
+#[cfg(feature = "heic")]
+pub mod heic;
 pub mod image;
 pub mod indexer;
+pub mod keeper;
+pub mod merge;
+pub mod motion;
+pub mod progress;
+pub mod raw;
+pub mod report;
 pub mod similarity;
+pub mod thumbnail;
+pub mod video;
+pub mod xmp;
 
 use log::LevelFilter;
 
@@ -1,3 +1,622 @@
-fn main() {
-    println!("Hello, world!");
+//! Command line interface for detecting and removing duplicate images.
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use image::GrayImage;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn, LevelFilter};
+
+use deduper::apply::{apply_report, link_report, undo, LinkMode};
+use deduper::cache::FingerprintCache;
+use deduper::cancel::CancellationToken;
+use deduper::duplicates::{find_media_duplicates, ScanOptions};
+use deduper::exact::same_contents;
+use deduper::filetype::{extension_mismatch, FileType};
+use deduper::image::Image;
+use deduper::indexer::{index_media, IndexerOptions, SymlinkPolicy};
+use deduper::keeper::{KeeperPolicy, KeeperRule};
+use deduper::merge::{merge_group, write_merged, ConflictRule};
+use deduper::progress::{NoProgress, Phase, Progress};
+use deduper::report::DuplicateReport;
+use deduper::setup_logger;
+use deduper::similarity::{ssim_index_with_metric, SsimMetric};
+
+#[derive(Parser)]
+#[command(
+    name = "deduper",
+    version,
+    about = "Detect and remove duplicate images and videos"
+)]
+struct Cli {
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, global = true, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Number of threads used to read and compare files, 0 using one per core
+    #[arg(long, global = true, default_value_t = 0)]
+    threads: usize,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Which files a scan indexes
+#[derive(Args)]
+struct IndexArgs {
+    /// Only scan files matching the pattern, e.g. "2023/**"; repeat to add patterns
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Skip files and folders matching the pattern, e.g. "@eaDir/"; repeat to add patterns
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Name of .gitignore-style files listing patterns to skip in their folder, e.g. .deduperignore
+    #[arg(long)]
+    ignore_file: Vec<String>,
+
+    /// Skip files and folders whose name starts with a dot
+    #[arg(long)]
+    skip_hidden: bool,
+
+    /// Maximum number of folders to descend, 1 scanning only the folder's own files
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Skip files smaller than this many bytes
+    #[arg(long)]
+    min_size: Option<u64>,
+
+    /// Skip files larger than this many bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    /// Symbolic links: skip, files (don't descend into linked folders) or follow
+    #[arg(long, default_value = "files")]
+    symlinks: SymlinkPolicy,
+
+    /// Recognise files by their content rather than their extension, including files with a
+    /// missing or wrong extension
+    #[arg(long)]
+    sniff: bool,
+}
+
+impl IndexArgs {
+    fn options(&self) -> Result<IndexerOptions, Box<dyn Error>> {
+        let mut options = IndexerOptions::new()
+            .skip_hidden(self.skip_hidden)
+            .symlinks(self.symlinks)
+            .by_content(self.sniff);
+        for pattern in &self.include {
+            options = options.include(pattern)?;
+        }
+        for pattern in &self.exclude {
+            options = options.exclude(pattern)?;
+        }
+        for name in &self.ignore_file {
+            options = options.ignore_file(name);
+        }
+        if let Some(depth) = self.max_depth {
+            options = options.max_depth(depth);
+        }
+        if let Some(bytes) = self.min_size {
+            options = options.min_size(bytes);
+        }
+        if let Some(bytes) = self.max_size {
+            options = options.max_size(bytes);
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Scan folders for duplicate images and videos and write a JSON report
+    Scan {
+        /// Folders to scan recursively, e.g. a library and its backups
+        #[arg(required = true)]
+        folders: Vec<PathBuf>,
+
+        /// The scanned folder whose copies are kept in preference to the others'
+        #[arg(long)]
+        primary: Option<PathBuf>,
+
+        /// Where to write the report
+        #[arg(short, long, default_value = "duplicates.json")]
+        output: PathBuf,
+
+        /// Minimum similarity score for two images to count as duplicates
+        #[arg(short, long, default_value_t = 0.95)]
+        threshold: f32,
+
+        /// Maximum perceptual hash distance for two images to be compared
+        #[arg(long, default_value_t = 10)]
+        max_distance: u32,
+
+        /// Similarity metric: global, gaussian or ms-ssim
+        #[arg(short, long, default_value = "global")]
+        metric: SsimMetric,
+
+        /// Fingerprint cache reused between scans
+        #[arg(long, default_value = "deduper-cache.json")]
+        cache: PathBuf,
+
+        /// Don't read or write the fingerprint cache
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Rules for choosing the file to keep, most important first:
+        /// primary, resolution, size, date, raw, motion, sidecar, path
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "primary,resolution,size,date,raw,motion,sidecar,path"
+        )]
+        keep: Vec<KeeperRule>,
+
+        /// Folder whose files are kept in preference to others; repeat to rank several
+        #[arg(long)]
+        prefer: Vec<PathBuf>,
+
+        /// Don't draw progress bars
+        #[arg(long)]
+        no_progress: bool,
+
+        #[command(flatten)]
+        index: Box<IndexArgs>,
+    },
+
+    /// Print the duplicate groups from a report
+    Report {
+        /// Report written by `scan`
+        report: PathBuf,
+    },
+
+    /// Compute the similarity of two images
+    Compare {
+        image1: PathBuf,
+        image2: PathBuf,
+
+        /// Similarity metric: global, gaussian or ms-ssim
+        #[arg(short, long, default_value = "global")]
+        metric: SsimMetric,
+
+        /// Save the per-pixel SSIM map as a greyscale image (windowed metrics only)
+        #[arg(long)]
+        map: Option<PathBuf>,
+    },
+
+    /// Print the resolution, orientation and metadata of images
+    Inspect {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Copy the metadata of each group's duplicates into the XMP sidecar of its keeper
+    Merge {
+        /// Report written by `scan`
+        report: PathBuf,
+
+        /// How to choose between conflicting values: keeper, common or earliest
+        #[arg(long, default_value = "keeper")]
+        conflict: ConflictRule,
+
+        /// Print the merged metadata without writing any sidecar
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Move all but the recommended file of each group, with their sidecars, into a quarantine
+    /// folder
+    Apply {
+        /// Report written by `scan`
+        report: PathBuf,
+
+        /// Folder the duplicates are moved to, mirroring their original location
+        #[arg(short, long, default_value = "deduper-quarantine")]
+        quarantine: PathBuf,
+
+        /// Instead of moving them, replace byte-identical duplicates with a hardlink or reflink
+        /// to the keeper: hardlink or reflink
+        #[arg(long)]
+        link: Option<LinkMode>,
+
+        /// List the files that would be moved or linked without changing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Move the files in a quarantine folder back to where they came from
+    Undo {
+        /// Folder given to `apply`
+        #[arg(default_value = "deduper-quarantine")]
+        quarantine: PathBuf,
+    },
+}
+
+/// Draws a progress bar on stderr for each phase of a scan, with a spinner while the total is
+/// unknown.
+#[derive(Default)]
+struct ProgressBars {
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl Progress for ProgressBars {
+    fn start(&self, phase: Phase, total: Option<u64>) {
+        let bar = match total {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{prefix:>14} [{elapsed_precise}] {wide_bar} {pos}/{len} ETA {eta}",
+                )
+                .unwrap(),
+            ),
+            None => {
+                let bar = ProgressBar::new_spinner().with_style(
+                    ProgressStyle::with_template(
+                        "{prefix:>14} [{elapsed_precise}] {spinner} {pos} files",
+                    )
+                    .unwrap(),
+                );
+                bar.enable_steady_tick(Duration::from_millis(100));
+                bar
+            }
+        };
+        bar.set_prefix(phase.to_string());
+
+        if let Some(previous) = self.bar.lock().unwrap().replace(bar) {
+            previous.finish();
+        }
+    }
+
+    fn advance(&self, _phase: Phase, count: u64) {
+        if let Some(bar) = &*self.bar.lock().unwrap() {
+            bar.inc(count);
+        }
+    }
+
+    fn finish(&self, _phase: Phase) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.finish();
+        }
+    }
+}
+
+/// Cancelled by the first Ctrl-C, stopping the scan at the next file.
+static INTERRUPT: OnceLock<CancellationToken> = OnceLock::new();
+
+#[cfg(target_os = "linux")]
+fn cancel_on_interrupt(cancel: &CancellationToken) {
+    extern "C" fn interrupted(_signal: libc::c_int) {
+        if let Some(cancel) = INTERRUPT.get() {
+            cancel.cancel();
+        }
+        // A second Ctrl-C ends the process straight away
+        // SAFETY: restoring the default handler is async-signal-safe
+        unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    }
+
+    if INTERRUPT.set(cancel.clone()).is_ok() {
+        // SAFETY: the handler only does atomic operations and async-signal-safe calls
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cancel_on_interrupt(_cancel: &CancellationToken) {}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    setup_logger(cli.log_level)?;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.threads)
+        .build_global()?;
+
+    match cli.command {
+        Command::Scan {
+            folders,
+            primary,
+            output,
+            threshold,
+            max_distance,
+            metric,
+            cache,
+            no_cache,
+            keep,
+            prefer,
+            no_progress,
+            index,
+        } => {
+            let cache = if no_cache {
+                FingerprintCache::in_memory()
+            } else {
+                FingerprintCache::open(&cache)?
+            };
+            let progress: Arc<dyn Progress> = if no_progress {
+                Arc::new(NoProgress)
+            } else {
+                Arc::new(ProgressBars::default())
+            };
+            let options = ScanOptions {
+                threshold,
+                max_hash_distance: max_distance,
+                metric,
+                keeper_policy: KeeperPolicy {
+                    rules: keep,
+                    preferred_paths: prefer,
+                    primary_root: primary.map(|primary| scanned_root(primary, &folders)),
+                },
+                roots: folders.clone(),
+                progress: progress.clone(),
+                ..ScanOptions::default()
+            };
+            let index = index.options()?.progress(progress);
+            cancel_on_interrupt(&options.cancel);
+            scan(folders, output, &index, &options, cache)
+        }
+        Command::Report { report } => report_groups(report),
+        Command::Compare {
+            image1,
+            image2,
+            metric,
+            map,
+        } => compare(image1, image2, metric, map),
+        Command::Inspect { paths } => inspect(paths),
+        Command::Merge {
+            report,
+            conflict,
+            dry_run,
+        } => merge(report, conflict, dry_run),
+        Command::Apply {
+            report,
+            quarantine,
+            link,
+            dry_run,
+        } => apply(report, quarantine, link, dry_run),
+        Command::Undo { quarantine } => undo_apply(quarantine),
+    }
+}
+
+// The scanned folder that is the same as `root`, however either is spelled, as files are tagged
+// with the folders
+fn scanned_root(root: PathBuf, folders: &[PathBuf]) -> PathBuf {
+    let canonical = fs::canonicalize(&root).ok();
+    let folder = folders.iter().find(|folder| {
+        **folder == root || canonical.is_some() && fs::canonicalize(folder).ok() == canonical
+    });
+
+    match folder {
+        Some(folder) => folder.clone(),
+        None => {
+            warn!("The primary root {} is not scanned", root.display());
+            root
+        }
+    }
+}
+
+fn scan(
+    folders: Vec<PathBuf>,
+    output: PathBuf,
+    index: &IndexerOptions,
+    options: &ScanOptions,
+    mut cache: FingerprintCache,
+) -> Result<(), Box<dyn Error>> {
+    let (image_paths, video_paths) = index_media(&folders, index);
+    info!(
+        "Found {} images and {} videos",
+        image_paths.len(),
+        video_paths.len()
+    );
+
+    let report = find_media_duplicates(&image_paths, &video_paths, options, &mut cache)
+        .inspect_err(|_| {
+            if options.cancel.is_cancelled() && cache.path().is_some() {
+                info!("Progress is saved in the cache, run the same scan again to resume");
+            }
+        })?;
+    cache.prune_missing();
+    cache.save()?;
+
+    report.save(&output)?;
+    info!(
+        "Wrote {} duplicate groups to {}",
+        report.groups.len(),
+        output.display()
+    );
+    if folders.len() > 1 {
+        info!(
+            "{} groups have copies in more than one folder",
+            report.cross_root_groups()
+        );
+    }
+
+    Ok(())
+}
+
+fn report_groups(report: PathBuf) -> Result<(), Box<dyn Error>> {
+    let report = DuplicateReport::load(&report)?;
+
+    for (i, group) in report.groups.iter().enumerate() {
+        match group.roots().len() {
+            0 | 1 => println!("Group {}", i + 1),
+            roots => println!("Group {} (across {} roots)", i + 1, roots),
+        }
+        for file in &group.files {
+            let marker = if file.path == group.keeper { "*" } else { "-" };
+            let resolution = file
+                .resolution
+                .map_or("?".to_string(), |(w, h)| format!("{}x{}", w, h));
+            let score = file.score.map_or("?".to_string(), |s| format!("{:.3}", s));
+            println!(
+                "{} {} ({} bytes, {}, score {})",
+                marker,
+                file.path.display(),
+                file.size,
+                resolution,
+                score
+            );
+            for companion in &file.companions {
+                println!("  + {}", companion.display());
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn compare(
+    path1: PathBuf,
+    path2: PathBuf,
+    metric: SsimMetric,
+    map_path: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let img1 = Image::from_path(&path1)?;
+    let img2 = Image::from_path(&path2)?;
+
+    let result = ssim_index_with_metric(&img1, &img2, metric, map_path.is_some())?;
+    println!("score = {}", result.score);
+
+    if let (Some(map), Some(map_path)) = (result.map, map_path) {
+        let pixels = map.pixels().map(|p| (p.0[0].clamp(0.0, 1.0) * 255.0) as u8);
+        GrayImage::from_raw(map.width(), map.height(), pixels.collect())
+            .expect("map dimensions match its data")
+            .save(&map_path)?;
+        info!("Saved SSIM map to {}", map_path.display());
+    }
+
+    Ok(())
+}
+
+fn inspect(paths: Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for path in &paths {
+        let img = Image::from_path(path)?;
+        println!("{}", path.display());
+        match FileType::detect(path)? {
+            Some(file_type) if extension_mismatch(path).is_some() => {
+                println!("- type: {} (extension mismatch)", file_type)
+            }
+            Some(file_type) => println!("- type: {}", file_type),
+            None => println!("- type: unknown"),
+        }
+        println!("- resolution: {:?}", img.resolution()?);
+        println!("- orientation: {:?}", img.orientation());
+        println!("- sidecar: {}", img.has_sidecar());
+        if let Ok(metadata) = img.metadata() {
+            for entry in &metadata.entries {
+                println!("- {}: {}", entry.tag, entry.value_more_readable);
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn merge(report: PathBuf, rule: ConflictRule, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    for group in DuplicateReport::load(&report)?.groups {
+        let result = merge_group(&group, rule);
+        for conflict in &result.conflicts {
+            println!(
+                "{}: conflicting {}, using {}",
+                group.keeper.display(),
+                conflict.field,
+                conflict.chosen
+            );
+            for (path, value) in &conflict.values {
+                println!("  {} {}", path.display(), value);
+            }
+        }
+
+        if !result.changed {
+            continue;
+        }
+        println!("merge  {} {:?}", group.keeper.display(), result.metadata);
+        if !dry_run {
+            write_merged(&group, &result)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn apply(
+    report: PathBuf,
+    quarantine: PathBuf,
+    link: Option<LinkMode>,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let report = DuplicateReport::load(&report)?;
+
+    if dry_run {
+        for group in &report.groups {
+            println!("keep   {}", group.keeper.display());
+            for file in group.duplicates() {
+                if link.is_some() {
+                    // Only exact duplicates are linked, without their companions
+                    if same_contents(&group.keeper, &file.path).unwrap_or(false) {
+                        println!(
+                            "link   {} -> {}",
+                            file.path.display(),
+                            group.keeper.display()
+                        );
+                    } else {
+                        println!("leave  {}", file.path.display());
+                    }
+                    continue;
+                }
+                println!("move   {}", file.path.display());
+                for companion in &file.companions {
+                    println!("move   {}", companion.display());
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(mode) = link {
+        let linked = link_report(&report, mode)?;
+        for file in &linked {
+            println!(
+                "linked {} -> {} ({:?})",
+                file.path.display(),
+                file.keeper.display(),
+                file.mode
+            );
+        }
+        info!("Linked {} files", linked.len());
+        return Ok(());
+    }
+
+    let journal = apply_report(&report, &quarantine)?;
+    for moved in &journal.moves {
+        println!("moved  {}", moved.from.display());
+    }
+    info!(
+        "Moved {} files to {}",
+        journal.moves.len(),
+        quarantine.display()
+    );
+
+    Ok(())
+}
+
+fn undo_apply(quarantine: PathBuf) -> Result<(), Box<dyn Error>> {
+    let restored = undo(&quarantine)?;
+    for moved in &restored.moves {
+        println!("restored {}", moved.from.display());
+    }
+    info!("Restored {} files", restored.moves.len());
+
+    Ok(())
 }
@@ -0,0 +1,356 @@
+//! Metadata merge.
+//! Collects the capture date, camera, location and keywords of every file in a duplicate group so
+//! that nothing is lost when the duplicates are removed. Values are written to the keeper's XMP
+//! sidecar, leaving the image itself untouched.
+
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::warn;
+
+use crate::error::AppError;
+use crate::exif::GpsPosition;
+use crate::image::Image;
+use crate::report::DuplicateGroup;
+use crate::xmp::{exif_to_xmp_date, sidecar_path, XmpMetadata};
+
+/// Positions closer than this, in degrees, are the same place (about 10 metres).
+const GPS_TOLERANCE: f64 = 1e-4;
+
+/// How a field is chosen when the files of a group disagree on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictRule {
+    /// The value of the best ranked file that has one, normally the keeper.
+    #[default]
+    PreferKeeper,
+    /// The value found on the most files, ties going to the better ranked file.
+    MostCommon,
+    /// The earliest capture date; other fields as `PreferKeeper`.
+    Earliest,
+}
+
+impl FromStr for ConflictRule {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keeper" => Ok(ConflictRule::PreferKeeper),
+            "common" => Ok(ConflictRule::MostCommon),
+            "earliest" => Ok(ConflictRule::Earliest),
+            _ => Err(AppError::UnknownConflictRule(s.to_string())),
+        }
+    }
+}
+
+/// A field on which the files of a group disagree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub field: &'static str,
+    /// Each file with a value for the field, in keeper order.
+    pub values: Vec<(PathBuf, String)>,
+    pub chosen: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeResult {
+    pub metadata: XmpMetadata,
+    pub conflicts: Vec<Conflict>,
+    /// Whether the merged metadata differs from the keeper's own.
+    pub changed: bool,
+}
+
+/// Read the metadata of an image from its EXIF data and its sidecar, the sidecar taking
+/// precedence as it holds later edits. Unreadable metadata is treated as missing.
+pub fn read_metadata(path: &Path) -> XmpMetadata {
+    let mut metadata = XmpMetadata::default();
+
+    if let Ok(exif) = Image::from_path(&path.to_path_buf()).and_then(|img| img.exif_summary()) {
+        metadata.date_time_original = exif.date_time_original.as_deref().map(exif_to_xmp_date);
+        metadata.make = exif.make;
+        metadata.model = exif.model;
+        metadata.gps = exif.gps;
+    }
+
+    let sidecar = sidecar_path(path);
+    if sidecar.exists() {
+        match XmpMetadata::read(&sidecar) {
+            Ok(xmp) => {
+                metadata.date_time_original =
+                    xmp.date_time_original.or(metadata.date_time_original);
+                metadata.make = xmp.make.or(metadata.make);
+                metadata.model = xmp.model.or(metadata.model);
+                metadata.gps = xmp.gps.or(metadata.gps);
+                metadata.keywords = xmp.keywords;
+            }
+            Err(e) => warn!("Ignoring sidecar {}: {}", sidecar.display(), e),
+        }
+    }
+
+    metadata
+}
+
+/// Merge the metadata of every file in a group. Keywords are combined, other fields are
+/// resolved by `rule` when the files disagree.
+pub fn merge_group(group: &DuplicateGroup, rule: ConflictRule) -> MergeResult {
+    let members: Vec<(PathBuf, XmpMetadata)> = group
+        .files
+        .iter()
+        .map(|file| (file.path.clone(), read_metadata(&file.path)))
+        .collect();
+
+    let mut conflicts = Vec::new();
+
+    let dates = field(&members, |m| m.date_time_original.clone());
+    let date_time_original = resolve(
+        "date",
+        dates,
+        |a, b| a.get(..19).unwrap_or(a) == b.get(..19).unwrap_or(b),
+        |date| date.clone(),
+        Some(|a, b| a.cmp(b)),
+        rule,
+        &mut conflicts,
+    );
+    let make = resolve(
+        "make",
+        field(&members, |m| m.make.clone()),
+        |a, b| a.eq_ignore_ascii_case(b),
+        |make| make.clone(),
+        None,
+        rule,
+        &mut conflicts,
+    );
+    let model = resolve(
+        "model",
+        field(&members, |m| m.model.clone()),
+        |a, b| a.eq_ignore_ascii_case(b),
+        |model| model.clone(),
+        None,
+        rule,
+        &mut conflicts,
+    );
+    let gps = resolve(
+        "gps",
+        field(&members, |m| m.gps),
+        same_position,
+        |gps| format!("{:.6}, {:.6}", gps.latitude, gps.longitude),
+        None,
+        rule,
+        &mut conflicts,
+    );
+
+    let mut keywords: Vec<String> = Vec::new();
+    for keyword in members.iter().flat_map(|(_, m)| &m.keywords) {
+        if !keywords.contains(keyword) {
+            keywords.push(keyword.clone());
+        }
+    }
+
+    let metadata = XmpMetadata {
+        date_time_original,
+        make,
+        model,
+        gps,
+        keywords,
+    };
+    let changed = members
+        .iter()
+        .find(|(path, _)| *path == group.keeper)
+        .is_none_or(|(_, keeper)| *keeper != metadata);
+
+    MergeResult {
+        metadata,
+        conflicts,
+        changed,
+    }
+}
+
+/// Write the merged metadata to the keeper's sidecar, creating it if necessary.
+pub fn write_merged(group: &DuplicateGroup, result: &MergeResult) -> Result<(), AppError> {
+    result.metadata.write(&sidecar_path(&group.keeper))
+}
+
+// The members having a value for a field, in keeper order
+fn field<T>(
+    members: &[(PathBuf, XmpMetadata)],
+    get: impl Fn(&XmpMetadata) -> Option<T>,
+) -> Vec<(PathBuf, T)> {
+    members
+        .iter()
+        .filter_map(|(path, metadata)| get(metadata).map(|value| (path.clone(), value)))
+        .collect()
+}
+
+// One value for a field, recording a conflict if the files disagree. `chronological` orders values
+// for `ConflictRule::Earliest`, which resolves fields without one as `PreferKeeper` does
+fn resolve<T: Clone>(
+    name: &'static str,
+    values: Vec<(PathBuf, T)>,
+    same: impl Fn(&T, &T) -> bool,
+    display: impl Fn(&T) -> String,
+    chronological: Option<fn(&T, &T) -> Ordering>,
+    rule: ConflictRule,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<T> {
+    // Distinct values with the number of files having each, in keeper order
+    let mut distinct: Vec<(&T, usize)> = Vec::new();
+    for (_, value) in &values {
+        match distinct.iter_mut().find(|(v, _)| same(v, value)) {
+            Some((_, count)) => *count += 1,
+            None => distinct.push((value, 1)),
+        }
+    }
+
+    let chosen = match rule {
+        _ if distinct.len() < 2 => distinct.first(),
+        ConflictRule::MostCommon => distinct.iter().rev().max_by_key(|(_, count)| *count),
+        ConflictRule::Earliest => match chronological {
+            Some(order) => distinct.iter().min_by(|(a, _), (b, _)| order(a, b)),
+            None => distinct.first(),
+        },
+        ConflictRule::PreferKeeper => distinct.first(),
+    }
+    .map(|(value, _)| (*value).clone());
+
+    if distinct.len() > 1 {
+        if let Some(chosen) = &chosen {
+            conflicts.push(Conflict {
+                field: name,
+                values: values
+                    .iter()
+                    .map(|(path, value)| (path.clone(), display(value)))
+                    .collect(),
+                chosen: display(chosen),
+            });
+        }
+    }
+
+    chosen
+}
+
+fn same_position(a: &GpsPosition, b: &GpsPosition) -> bool {
+    (a.latitude - b.latitude).abs() < GPS_TOLERANCE
+        && (a.longitude - b.longitude).abs() < GPS_TOLERANCE
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ReportedFile;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_merge_into_keeper() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let keeper = dir.join("keeper.jpg");
+        let copy = dir.join("copy.jpg");
+        fs::write(&keeper, b"").unwrap();
+        fs::write(&copy, b"").unwrap();
+        XmpMetadata {
+            keywords: vec!["holiday".to_string()],
+            ..XmpMetadata::default()
+        }
+        .write(&sidecar_path(&keeper))
+        .unwrap();
+        XmpMetadata {
+            gps: Some(GpsPosition {
+                latitude: 55.96,
+                longitude: -3.28,
+            }),
+            keywords: vec!["edinburgh".to_string(), "holiday".to_string()],
+            ..XmpMetadata::default()
+        }
+        .write(&sidecar_path(&copy))
+        .unwrap();
+
+        let group = group(&[&keeper, &copy]);
+        let result = merge_group(&group, ConflictRule::default());
+        assert!(result.changed);
+        assert!(result.conflicts.is_empty());
+        write_merged(&group, &result).unwrap();
+        // The sidecar was replaced, leaving no temporary file behind
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 4);
+
+        let merged = read_metadata(&keeper);
+        assert_eq!(merged.keywords, vec!["holiday", "edinburgh"]);
+        assert!(merged
+            .gps
+            .is_some_and(|gps| (gps.latitude - 55.96).abs() < 1e-6));
+
+        // Nothing left to merge
+        assert!(!merge_group(&group, ConflictRule::default()).changed);
+    }
+
+    #[test]
+    fn test_conflict_rules() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let dates = [
+            ("a.jpg", "2020-06-01T12:00:00"),
+            ("b.jpg", "2019-01-01T08:00:00"),
+            ("c.jpg", "2019-01-01T08:00:00.500"),
+        ];
+        let mut paths = Vec::new();
+        for (name, date) in dates {
+            let path = dir.join(name);
+            fs::write(&path, b"").unwrap();
+            XmpMetadata {
+                date_time_original: Some(date.to_string()),
+                ..XmpMetadata::default()
+            }
+            .write(&sidecar_path(&path))
+            .unwrap();
+            paths.push(path);
+        }
+        let group = group(&paths.iter().collect::<Vec<_>>());
+
+        let keeper = merge_group(&group, ConflictRule::PreferKeeper);
+        assert_eq!(keeper.conflicts.len(), 1);
+        assert_eq!(keeper.conflicts[0].field, "date");
+        assert_eq!(keeper.conflicts[0].values.len(), 3);
+        assert_eq!(
+            keeper.metadata.date_time_original.as_deref(),
+            Some("2020-06-01T12:00:00")
+        );
+        assert!(!keeper.changed);
+
+        let common = merge_group(&group, "common".parse().unwrap());
+        assert_eq!(
+            common.metadata.date_time_original.as_deref(),
+            Some("2019-01-01T08:00:00")
+        );
+        assert!(common.changed);
+
+        let earliest = merge_group(&group, ConflictRule::Earliest);
+        assert_eq!(
+            earliest.metadata.date_time_original.as_deref(),
+            Some("2019-01-01T08:00:00")
+        );
+        assert!("newest".parse::<ConflictRule>().is_err());
+    }
+
+    // A group in the given order, the first path being the keeper
+    fn group(paths: &[&PathBuf]) -> DuplicateGroup {
+        DuplicateGroup {
+            keeper: paths[0].clone(),
+            files: paths
+                .iter()
+                .map(|path| ReportedFile {
+                    path: path.to_path_buf(),
+                    size: 0,
+                    resolution: None,
+                    score: None,
+                    date_time_original: None,
+                    sidecar: true,
+                    companions: Vec::new(),
+                    motion: false,
+                    root: None,
+                })
+                .collect(),
+        }
+    }
+}
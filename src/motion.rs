@@ -0,0 +1,320 @@
+//! Live Photos and motion photos.
+//! An iPhone Live Photo is a still and a short `.mov` written side by side, both carrying the same
+//! content identifier: the still in its Apple maker note, the video in its QuickTime metadata.
+//! Android motion photos append the video to the JPEG itself and announce it in the XMP metadata.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use rayon::prelude::*;
+use rexif::ExifTag;
+
+use crate::image::Image;
+use crate::video::Video;
+
+/// Signature of the XMP packet in a JPEG APP1 segment.
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// XMP properties marking a JPEG with an embedded video, in attribute and element form.
+const MOTION_PHOTO_MARKERS: [&[u8]; 4] = [
+    b"MotionPhoto=\"1\"",
+    b"MicroVideo=\"1\"",
+    b"MotionPhoto>1<",
+    b"MicroVideo>1<",
+];
+
+/// The still and video of a Live Photo. The two are a single shot rather than separate files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LivePhoto {
+    pub still: PathBuf,
+    pub video: PathBuf,
+}
+
+/// Pair each video with the still in the same folder that has the same content identifier,
+/// preferring a still with the same name when several have it.
+pub fn find_live_photos(stills: &[PathBuf], videos: &[PathBuf]) -> Vec<LivePhoto> {
+    // Videos by folder and content identifier
+    let identifiers: Vec<_> = videos
+        .par_iter()
+        .map(|video| Video::from_path(video).content_identifier())
+        .collect();
+    let mut videos_by_id: HashMap<(PathBuf, String), Vec<&PathBuf>> = HashMap::new();
+    for (video, identifier) in videos.iter().zip(identifiers) {
+        match identifier {
+            Ok(Some(id)) => videos_by_id
+                .entry((folder(video), id))
+                .or_default()
+                .push(video),
+            Ok(None) => {}
+            Err(e) => debug!("No content identifier for {}: {}", video.display(), e),
+        }
+    }
+
+    // Only stills next to a Live Photo video need their metadata read
+    let folders: HashSet<PathBuf> = videos_by_id
+        .keys()
+        .map(|(folder, _)| folder.clone())
+        .collect();
+    let mut candidates: Vec<(&PathBuf, (PathBuf, String))> = stills
+        .par_iter()
+        .filter(|still| folders.contains(&folder(still)))
+        .filter_map(|still| content_identifier(still).map(|id| (still, (folder(still), id))))
+        .collect();
+
+    let mut pairs = Vec::new();
+    for same_name in [true, false] {
+        candidates.retain(|(still, key)| {
+            let Some(videos) = videos_by_id.get_mut(key) else {
+                return false;
+            };
+            let position = videos
+                .iter()
+                .position(|video| !same_name || video.file_stem() == still.file_stem());
+            match position {
+                Some(i) => {
+                    pairs.push(LivePhoto {
+                        still: (*still).clone(),
+                        video: videos.remove(i).clone(),
+                    });
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+    pairs.sort_by(|a, b| a.still.cmp(&b.still));
+
+    pairs
+}
+
+/// Returns the Live Photo content identifier stored in the Apple maker note of a still.
+pub fn content_identifier(still: &Path) -> Option<String> {
+    let metadata = Image::from_path(&still.to_path_buf())
+        .and_then(|img| img.metadata())
+        .ok()?;
+    let maker_note = metadata
+        .entries
+        .iter()
+        .find(|entry| entry.tag == ExifTag::MakerNote)?;
+
+    apple_content_identifier(&maker_note.ifd.data)
+}
+
+/// Returns true if the file is a JPEG with an embedded video, such as an Android motion photo.
+pub fn is_motion_photo(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    if extension != "jpg" && extension != "jpeg" {
+        return false;
+    }
+
+    jpeg_xmp(path).is_some_and(|xmp| {
+        MOTION_PHOTO_MARKERS
+            .iter()
+            .any(|marker| xmp.windows(marker.len()).any(|window| window == *marker))
+    })
+}
+
+// The maker note is "Apple iOS", a version, a byte order mark and an IFD whose offsets are
+// relative to the start of the maker note. The content identifier is tag 0x11.
+fn apple_content_identifier(maker_note: &[u8]) -> Option<String> {
+    if !maker_note.starts_with(b"Apple iOS\0") {
+        return None;
+    }
+    let le = maker_note.get(12..14)? == b"II";
+    let u16_at = |offset: usize| {
+        let bytes = maker_note.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if le {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |offset: usize| {
+        let bytes = maker_note.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if le {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    let count = u16_at(14)? as usize;
+    let entry = (0..count)
+        .map(|i| 16 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(0x11))?;
+
+    let len = u32_at(entry + 4)? as usize;
+    let start = if len <= 4 {
+        entry + 8
+    } else {
+        u32_at(entry + 8)? as usize
+    };
+    let value = maker_note.get(start..start + len)?;
+
+    let id = String::from_utf8_lossy(value)
+        .trim_end_matches('\0')
+        .to_string();
+    (!id.is_empty()).then_some(id)
+}
+
+// The XMP packet from the APP1 segments before the image data
+fn jpeg_xmp(path: &Path) -> Option<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut soi = [0u8; 2];
+    reader.read_exact(&mut soi).ok()?;
+    if soi != [0xff, 0xd8] {
+        return None;
+    }
+
+    loop {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header).ok()?;
+        // Start of scan or end of image
+        if header[0] != 0xff || header[1] == 0xda || header[1] == 0xd9 {
+            return None;
+        }
+        let len = (u16::from_be_bytes([header[2], header[3]]) as usize).checked_sub(2)?;
+        let mut segment = vec![0u8; len];
+        reader.read_exact(&mut segment).ok()?;
+
+        if header[1] == 0xe1 && segment.starts_with(XMP_SIGNATURE) {
+            return Some(segment.split_off(XMP_SIGNATURE.len()));
+        }
+    }
+}
+
+fn folder(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::video::tests::live_photo_mov;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const ID: &str = "A1B2C3D4-0000-4000-8000-000000000001";
+
+    #[test]
+    fn test_find_live_photos() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        for folder in ["a", "b"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        let still = live_photo_jpeg(ID);
+        // A copy of the still sorting first, and a copy of the shot in another folder
+        for path in ["a/IMG_0001 copy.JPG", "a/IMG_0001.JPG", "b/IMG_0001.JPG"] {
+            fs::write(dir.join(path), &still).unwrap();
+        }
+        for path in ["a/IMG_0001.MOV", "b/IMG_0001.MOV"] {
+            fs::write(dir.join(path), live_photo_mov(ID)).unwrap();
+        }
+        fs::copy(
+            "test-data/orientation/orientation-1.jpg",
+            dir.join("b/IMG_0002.JPG"),
+        )
+        .unwrap();
+
+        let stills: Vec<PathBuf> = [
+            "a/IMG_0001 copy.JPG",
+            "a/IMG_0001.JPG",
+            "b/IMG_0001.JPG",
+            "b/IMG_0002.JPG",
+        ]
+        .iter()
+        .map(|path| dir.join(path))
+        .collect();
+        let videos = vec![dir.join("a/IMG_0001.MOV"), dir.join("b/IMG_0001.MOV")];
+
+        assert_eq!(
+            content_identifier(&dir.join("a/IMG_0001.JPG")).as_deref(),
+            Some(ID)
+        );
+        assert_eq!(
+            find_live_photos(&stills, &videos),
+            vec![
+                LivePhoto {
+                    still: dir.join("a/IMG_0001.JPG"),
+                    video: dir.join("a/IMG_0001.MOV"),
+                },
+                LivePhoto {
+                    still: dir.join("b/IMG_0001.JPG"),
+                    video: dir.join("b/IMG_0001.MOV"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_motion_photo() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+
+        let path = dir.join("PXL_0001.MP.jpg");
+        fs::write(&path, motion_photo_jpeg()).unwrap();
+        assert!(is_motion_photo(&path));
+        assert!(!is_motion_photo(Path::new(
+            "test-data/orientation/orientation-1.jpg"
+        )));
+    }
+
+    /// A JPEG whose EXIF metadata has an Apple maker note with a content identifier.
+    pub(crate) fn live_photo_jpeg(identifier: &str) -> Vec<u8> {
+        // Maker note with its IFD of a single ASCII entry, the value following the IFD
+        let mut maker_note = b"Apple iOS\0\0\x01MM".to_vec();
+        maker_note.extend(1u16.to_be_bytes());
+        maker_note.extend([0x00, 0x11, 0x00, 0x02]);
+        maker_note.extend((identifier.len() as u32 + 1).to_be_bytes());
+        maker_note.extend(32u32.to_be_bytes());
+        maker_note.extend(0u32.to_be_bytes());
+        maker_note.extend(identifier.as_bytes());
+        maker_note.push(0);
+
+        // IFD0 pointing to the EXIF IFD, which holds the maker note
+        let mut tiff = b"MM\0\x2a".to_vec();
+        tiff.extend(8u32.to_be_bytes());
+        tiff.extend(1u16.to_be_bytes());
+        tiff.extend([0x87, 0x69, 0x00, 0x04, 0, 0, 0, 1]);
+        tiff.extend(26u32.to_be_bytes());
+        tiff.extend(0u32.to_be_bytes());
+        tiff.extend(1u16.to_be_bytes());
+        tiff.extend([0x92, 0x7c, 0x00, 0x07]);
+        tiff.extend((maker_note.len() as u32).to_be_bytes());
+        tiff.extend(44u32.to_be_bytes());
+        tiff.extend(0u32.to_be_bytes());
+        tiff.extend(maker_note);
+
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend(tiff);
+        with_app1(&exif)
+    }
+
+    fn motion_photo_jpeg() -> Vec<u8> {
+        let mut xmp = XMP_SIGNATURE.to_vec();
+        xmp.extend(
+            br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description xmlns:GCamera="http://ns.google.com/photos/1.0/camera/" GCamera:MotionPhoto="1" GCamera:MotionPhotoVersion="1"/></rdf:RDF></x:xmpmeta>"#,
+        );
+        with_app1(&xmp)
+    }
+
+    // A fixture image with an APP1 segment inserted after the start of image marker
+    fn with_app1(segment: &[u8]) -> Vec<u8> {
+        let jpeg = fs::read("test-data/orientation/orientation-1.jpg").unwrap();
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe1];
+        data.extend(((segment.len() + 2) as u16).to_be_bytes());
+        data.extend(segment);
+        data.extend(&jpeg[2..]);
+        data
+    }
+}
@@ -0,0 +1,123 @@
+//! Progress reporting for long scans.
+//! The library reports what it is doing through the `Progress` trait, so that the command line can
+//! draw progress bars and applications embedding the library can show their own progress and ETA.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The stages of a scan, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Walking folders for images and videos.
+    Indexing,
+    /// Decoding images and computing their fingerprints, unless they are cached.
+    Fingerprinting,
+    /// Computing the similarity of candidate pairs.
+    Comparing,
+    /// Decoding frames of videos and computing their fingerprints.
+    Videos,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Indexing => "Indexing",
+            Phase::Fingerprinting => "Fingerprinting",
+            Phase::Comparing => "Comparing",
+            Phase::Videos => "Videos",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Receives the progress of a scan. Methods are called from worker threads, and do nothing by
+/// default.
+pub trait Progress: Send + Sync {
+    /// A phase starts with `total` items to process, or an unknown number while indexing.
+    fn start(&self, _phase: Phase, _total: Option<u64>) {}
+
+    /// `count` more items of the phase are done.
+    fn advance(&self, _phase: Phase, _count: u64) {}
+
+    fn finish(&self, _phase: Phase) {}
+}
+
+impl fmt::Debug for dyn Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Progress")
+    }
+}
+
+/// Ignores all progress.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// Calls a function with the phase, the number of items done and the total after every update,
+/// for applications that only need a callback.
+pub struct ProgressFn<F> {
+    callback: F,
+    total: AtomicU64,
+    done: AtomicU64,
+}
+
+impl<F: Fn(Phase, u64, Option<u64>) + Send + Sync> ProgressFn<F> {
+    pub fn new(callback: F) -> ProgressFn<F> {
+        ProgressFn {
+            callback,
+            total: AtomicU64::new(u64::MAX),
+            done: AtomicU64::new(0),
+        }
+    }
+
+    fn total(&self) -> Option<u64> {
+        Some(self.total.load(Ordering::Relaxed)).filter(|&total| total != u64::MAX)
+    }
+}
+
+impl<F: Fn(Phase, u64, Option<u64>) + Send + Sync> Progress for ProgressFn<F> {
+    fn start(&self, phase: Phase, total: Option<u64>) {
+        self.total
+            .store(total.unwrap_or(u64::MAX), Ordering::Relaxed);
+        self.done.store(0, Ordering::Relaxed);
+        (self.callback)(phase, 0, total);
+    }
+
+    fn advance(&self, phase: Phase, count: u64) {
+        let done = self.done.fetch_add(count, Ordering::Relaxed) + count;
+        (self.callback)(phase, done, self.total());
+    }
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_progress_fn() {
+        let updates = Mutex::new(Vec::new());
+        let progress = ProgressFn::new(|phase, done, total| {
+            updates.lock().unwrap().push((phase, done, total))
+        });
+
+        progress.start(Phase::Indexing, None);
+        progress.advance(Phase::Indexing, 2);
+        progress.start(Phase::Comparing, Some(3));
+        progress.advance(Phase::Comparing, 1);
+        progress.finish(Phase::Comparing);
+
+        assert_eq!(
+            updates.into_inner().unwrap(),
+            vec![
+                (Phase::Indexing, 0, None),
+                (Phase::Indexing, 2, None),
+                (Phase::Comparing, 0, Some(3)),
+                (Phase::Comparing, 1, Some(3)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! RAW camera files.
+//! NEF, CR2, ARW, DNG and similar formats are TIFF files holding the sensor data alongside one or
+//! more JPEG previews rendered by the camera. Rather than developing the sensor data, the largest
+//! preview stands in for the image when hashing and comparing.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use image::{DynamicImage, ImageFormat, ImageReader};
+use log::debug;
+
+use crate::error::AppError;
+
+pub const EXTENSIONS: [&str; 6] = ["nef", "nrw", "cr2", "arw", "dng", "pef"];
+
+const NEW_SUBFILE_TYPE: u16 = 0x00fe;
+const COMPRESSION: u16 = 0x0103;
+const PHOTOMETRIC_INTERPRETATION: u16 = 0x0106;
+const STRIP_OFFSETS: u16 = 0x0111;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const SUB_IFDS: u16 = 0x014a;
+const JPEG_OFFSET: u16 = 0x0201;
+const JPEG_LENGTH: u16 = 0x0202;
+
+/// Old and new style JPEG compression.
+const JPEG_COMPRESSION: [u32; 2] = [6, 7];
+/// Colour filter array and linear raw photometric interpretations, used by sensor data.
+const SENSOR_DATA: [u32; 2] = [32803, 34892];
+
+/// Limit on the number of IFDs visited, in case of loops in a corrupt file.
+const MAX_IFDS: usize = 64;
+
+/// Returns true if the file has the extension of a supported RAW format.
+pub fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Returns the largest embedded JPEG preview that can be decoded.
+pub fn preview(path: &Path) -> Result<Vec<u8>, AppError> {
+    let mut tiff = Tiff::open(path)?;
+
+    let mut candidates = tiff.jpeg_candidates()?;
+    candidates.sort_by_key(|&(_, length)| std::cmp::Reverse(length));
+
+    for (offset, length) in candidates {
+        let data = tiff.read_at(offset, length)?;
+        if is_decodable_jpeg(&data) {
+            debug!("Using {} byte preview at {}", length, offset);
+            return Ok(data);
+        }
+    }
+
+    Err(AppError::MissingPreview)
+}
+
+/// Decode the largest embedded preview. The camera's orientation is not applied.
+pub fn decode_preview(path: &Path) -> Result<DynamicImage, AppError> {
+    Ok(image::load_from_memory_with_format(
+        &preview(path)?,
+        ImageFormat::Jpeg,
+    )?)
+}
+
+/// Returns the dimensions of the largest embedded preview without decoding it.
+pub fn preview_dimensions(path: &Path) -> Result<(u32, u32), AppError> {
+    let reader = ImageReader::with_format(Cursor::new(preview(path)?), ImageFormat::Jpeg);
+    Ok(reader.into_dimensions()?)
+}
+
+// A JPEG starting with an image marker and using a baseline, extended or progressive frame, as
+// opposed to the lossless JPEG some cameras store their sensor data in
+fn is_decodable_jpeg(data: &[u8]) -> bool {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return false;
+    }
+
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xff {
+            return false;
+        }
+        let marker = data[i + 1];
+        match marker {
+            0xc0..=0xc2 => return true,
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => return false,
+            // Fill bytes
+            0xff => i += 1,
+            _ => i += 2 + u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize,
+        }
+    }
+
+    false
+}
+
+struct Tiff {
+    reader: BufReader<File>,
+    little_endian: bool,
+}
+
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    // The value itself if it fits in four bytes, otherwise its offset
+    value: [u8; 4],
+}
+
+impl Tiff {
+    fn open(path: &Path) -> Result<Tiff, AppError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+
+        let little_endian = match header {
+            [b'I', b'I', 42, 0] => true,
+            [b'M', b'M', 0, 42] => false,
+            _ => {
+                return Err(AppError::UnsupportedType(format!(
+                    "{} is not a TIFF based RAW file",
+                    path.display()
+                )))
+            }
+        };
+
+        Ok(Tiff {
+            reader,
+            little_endian,
+        })
+    }
+
+    // Offsets and lengths of the JPEG images in every IFD, following both the chain of IFDs from
+    // the header and their sub-IFDs
+    fn jpeg_candidates(&mut self) -> Result<Vec<(u64, u64)>, AppError> {
+        let mut candidates = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![self.read_u32_at(4)?];
+
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || visited.len() >= MAX_IFDS || !visited.insert(offset) {
+                continue;
+            }
+
+            let (entries, next) = self.read_ifd(offset)?;
+            pending.push(next);
+
+            let mut values = |tag: u16| -> Result<Vec<u32>, AppError> {
+                match entries.iter().find(|entry| entry.tag == tag) {
+                    Some(entry) => self.values(entry),
+                    None => Ok(Vec::new()),
+                }
+            };
+            let first = |values: Vec<u32>| values.first().copied();
+
+            pending.extend(values(SUB_IFDS)?);
+
+            if let (Some(offset), Some(length)) =
+                (first(values(JPEG_OFFSET)?), first(values(JPEG_LENGTH)?))
+            {
+                candidates.push((offset as u64, length as u64));
+            }
+
+            let compression = first(values(COMPRESSION)?);
+            let photometric = first(values(PHOTOMETRIC_INTERPRETATION)?);
+            let subfile_type = first(values(NEW_SUBFILE_TYPE)?).unwrap_or(0);
+            let is_sensor_data = photometric.is_some_and(|p| SENSOR_DATA.contains(&p));
+            let strips = (values(STRIP_OFFSETS)?, values(STRIP_BYTE_COUNTS)?);
+            if compression.is_some_and(|c| JPEG_COMPRESSION.contains(&c))
+                && !is_sensor_data
+                && subfile_type & 0b100 == 0
+            {
+                // A preview is stored as a single strip
+                if let ([offset], [length]) = (strips.0.as_slice(), strips.1.as_slice()) {
+                    candidates.push((*offset as u64, *length as u64));
+                }
+            }
+        }
+
+        Ok(candidates)
+    }
+
+    fn read_ifd(&mut self, offset: u32) -> Result<(Vec<IfdEntry>, u32), AppError> {
+        let count = self.read_u16_at(offset as u64)?;
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut entry = [0u8; 12];
+            self.reader.read_exact(&mut entry)?;
+            entries.push(IfdEntry {
+                tag: self.u16(&entry[0..2]),
+                field_type: self.u16(&entry[2..4]),
+                count: self.u32(&entry[4..8]),
+                value: entry[8..12].try_into().expect("four bytes"),
+            });
+        }
+
+        let mut next = [0u8; 4];
+        self.reader.read_exact(&mut next)?;
+        Ok((entries, self.u32(&next)))
+    }
+
+    // The values of a SHORT, LONG or IFD entry
+    fn values(&mut self, entry: &IfdEntry) -> Result<Vec<u32>, AppError> {
+        let size = match entry.field_type {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Ok(Vec::new()),
+        };
+        // Guard against absurd counts in corrupt files
+        let count = entry.count.min(1024) as usize;
+
+        let data = if size * count <= 4 {
+            entry.value[..size * count].to_vec()
+        } else {
+            self.read_at(self.u32(&entry.value) as u64, (size * count) as u64)?
+        };
+
+        Ok(data
+            .chunks_exact(size)
+            .map(|value| match size {
+                2 => self.u16(value) as u32,
+                _ => self.u32(value),
+            })
+            .collect())
+    }
+
+    fn read_at(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, AppError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        (&mut self.reader).take(length).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_u16_at(&mut self, offset: u64) -> Result<u16, AppError> {
+        let data = self.read_exact_at(offset, 2)?;
+        Ok(self.u16(&data))
+    }
+
+    fn read_u32_at(&mut self, offset: u64) -> Result<u32, AppError> {
+        let data = self.read_exact_at(offset, 4)?;
+        Ok(self.u32(&data))
+    }
+
+    fn read_exact_at(&mut self, offset: u64, length: usize) -> Result<Vec<u8>, AppError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; length];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::image::{Image, Orientation};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::RgbImage;
+    use std::{fs, path::PathBuf};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_largest_decodable_preview() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("raw.dng");
+        write_raw(&path);
+
+        assert!(is_raw(&path));
+        assert_eq!(preview_dimensions(&path).unwrap(), (64, 32));
+
+        // The camera's orientation is applied to the preview
+        let img = Image::from_path(&path).unwrap();
+        assert_eq!(img.orientation(), Orientation::Rotated90);
+        assert_eq!(img.resolution().unwrap(), (32, 64));
+        let pixels = img.image().unwrap();
+        assert_eq!((pixels.width(), pixels.height()), (32, 64));
+    }
+
+    #[test]
+    fn test_not_a_raw_file() {
+        let path = PathBuf::from("test-data/01/house.jpg");
+        assert!(!is_raw(&path));
+        assert!(preview(&path).is_err());
+    }
+
+    // A little-endian TIFF with the orientation and a sensor data strip in IFD0, a small preview
+    // in IFD1, a larger one in a sub-IFD and a lossless JPEG, which can't be decoded, in another
+    pub(crate) fn write_raw(path: &Path) {
+        let small = jpeg(32, 16);
+        let large = jpeg(64, 32);
+        let mut lossless = vec![0xff, 0xd8, 0xff, 0xc3, 0x00, 0x04, 0x00, 0x00];
+        lossless.resize(4 * large.len(), 0);
+
+        let entry = |tag: u16, field_type: u16, count: u32, value: u32| {
+            let mut bytes = Vec::new();
+            bytes.extend(tag.to_le_bytes());
+            bytes.extend(field_type.to_le_bytes());
+            bytes.extend(count.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+            bytes
+        };
+        let ifd = |entries: Vec<Vec<u8>>, next: u32| {
+            let mut bytes = (entries.len() as u16).to_le_bytes().to_vec();
+            bytes.extend(entries.concat());
+            bytes.extend(next.to_le_bytes());
+            bytes
+        };
+
+        // IFDs of 4 entries take 54 bytes
+        let (ifd0, ifd1, sub1, sub2) = (8u32, 62u32, 116u32, 170u32);
+        let small_at = 224u32;
+        let large_at = small_at + small.len() as u32;
+        let lossless_at = large_at + large.len() as u32;
+
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(ifd0.to_le_bytes());
+        tiff.extend(ifd(
+            vec![
+                entry(COMPRESSION, 3, 1, 7),
+                entry(PHOTOMETRIC_INTERPRETATION, 3, 1, 32803),
+                entry(0x0112, 3, 1, 6),
+                entry(SUB_IFDS, 4, 1, sub1),
+            ],
+            ifd1,
+        ));
+        tiff.extend(ifd(
+            vec![
+                entry(NEW_SUBFILE_TYPE, 4, 1, 1),
+                entry(COMPRESSION, 3, 1, 6),
+                entry(JPEG_OFFSET, 4, 1, small_at),
+                entry(JPEG_LENGTH, 4, 1, small.len() as u32),
+            ],
+            sub2,
+        ));
+        tiff.extend(ifd(
+            vec![
+                entry(NEW_SUBFILE_TYPE, 4, 1, 1),
+                entry(COMPRESSION, 3, 1, 7),
+                entry(STRIP_OFFSETS, 4, 1, large_at),
+                entry(STRIP_BYTE_COUNTS, 4, 1, large.len() as u32),
+            ],
+            0,
+        ));
+        tiff.extend(ifd(
+            vec![
+                entry(NEW_SUBFILE_TYPE, 4, 1, 0),
+                entry(COMPRESSION, 3, 1, 6),
+                entry(STRIP_OFFSETS, 4, 1, lossless_at),
+                entry(STRIP_BYTE_COUNTS, 4, 1, lossless.len() as u32),
+            ],
+            0,
+        ));
+        assert_eq!(tiff.len(), small_at as usize);
+        tiff.extend(small);
+        tiff.extend(large);
+        tiff.extend(lossless);
+
+        fs::write(path, tiff).unwrap();
+    }
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([x as u8 * 4, y as u8 * 4, 0])
+        });
+        let mut data = Vec::new();
+        JpegEncoder::new(&mut data).encode_image(&img).unwrap();
+        data
+    }
+}
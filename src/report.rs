@@ -0,0 +1,305 @@
+//! Duplicate report.
+//! The report is written as JSON. Besides the detailed groups it lists the bare paths of each
+//! group under `paths`, the shape of earlier reports, which can still be read back.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::keeper::KeeperPolicy;
+use crate::motion::is_motion_photo;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateReport {
+    #[serde(default)]
+    pub groups: Vec<DuplicateGroup>,
+    /// The paths of each group.
+    pub paths: Vec<Vec<PathBuf>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    /// The file recommended to keep.
+    pub keeper: PathBuf,
+    pub files: Vec<ReportedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportedFile {
+    pub path: PathBuf,
+    /// Size in bytes.
+    pub size: u64,
+    /// Resolution after applying the orientation.
+    pub resolution: Option<(u32, u32)>,
+    /// Highest similarity to another file in the group, 1.0 for identical images.
+    pub score: Option<f32>,
+    /// Capture date from the EXIF metadata.
+    #[serde(default)]
+    pub date_time_original: Option<String>,
+    /// Whether the file has an XMP sidecar.
+    #[serde(default)]
+    pub sidecar: bool,
+    /// Files of the same shot, such as the RAW file of a JPEG or the video of a Live Photo, kept or
+    /// removed along with it.
+    #[serde(default)]
+    pub companions: Vec<PathBuf>,
+    /// Whether the file embeds a video, as Android motion photos do.
+    #[serde(default)]
+    pub motion: bool,
+    /// The scanned folder the file was found under.
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+}
+
+impl DuplicateReport {
+    pub fn new(groups: Vec<DuplicateGroup>) -> DuplicateReport {
+        let paths = groups
+            .iter()
+            .map(|group| group.files.iter().map(|file| file.path.clone()).collect())
+            .collect();
+
+        DuplicateReport { groups, paths }
+    }
+
+    /// Read a report. Reports that only list paths get one group per list, with the file sizes
+    /// read from disk.
+    pub fn load(path: &Path) -> Result<DuplicateReport, AppError> {
+        let reader = BufReader::new(File::open(path)?);
+        let report: DuplicateReport = serde_json::from_reader(reader)?;
+
+        if report.groups.is_empty() && !report.paths.is_empty() {
+            let groups = report
+                .paths
+                .into_iter()
+                .map(|paths| {
+                    DuplicateGroup::new(
+                        paths
+                            .into_iter()
+                            .map(|path| ReportedFile {
+                                size: fs::metadata(&path).map_or(0, |m| m.len()),
+                                sidecar: path.with_extension("xmp").exists(),
+                                companions: Vec::new(),
+                                motion: is_motion_photo(&path),
+                                root: None,
+                                path,
+                                resolution: None,
+                                score: None,
+                                date_time_original: None,
+                            })
+                            .collect(),
+                    )
+                })
+                .collect();
+            return Ok(DuplicateReport::new(groups));
+        }
+
+        Ok(report)
+    }
+
+    /// Add groups found separately, e.g. duplicate videos.
+    pub fn extend(&mut self, groups: Vec<DuplicateGroup>) {
+        self.paths.extend(
+            groups
+                .iter()
+                .map(|group| group.files.iter().map(|file| file.path.clone()).collect()),
+        );
+        self.groups.extend(groups);
+    }
+
+    /// Record which of `roots` each file was found under, the deepest one if they are nested.
+    pub fn tag_roots(&mut self, roots: &[PathBuf]) {
+        for file in self.groups.iter_mut().flat_map(|group| &mut group.files) {
+            file.root = root_of(&file.path, roots);
+        }
+    }
+
+    /// Returns the number of groups with files under more than one root.
+    pub fn cross_root_groups(&self) -> usize {
+        self.groups
+            .iter()
+            .filter(|group| group.roots().len() > 1)
+            .count()
+    }
+
+    /// Choose the keeper of every group again, e.g. with different rules than the scan used.
+    pub fn apply_policy(&mut self, policy: &KeeperPolicy) {
+        let groups = self
+            .groups
+            .drain(..)
+            .map(|group| DuplicateGroup::with_policy(group.files, policy))
+            .collect();
+        *self = DuplicateReport::new(groups);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+
+        Ok(())
+    }
+}
+
+impl DuplicateGroup {
+    /// Create a group, choosing the keeper with the default policy.
+    pub fn new(files: Vec<ReportedFile>) -> DuplicateGroup {
+        DuplicateGroup::with_policy(files, &KeeperPolicy::default())
+    }
+
+    /// Create a group with its files ordered from best to worst keeper.
+    pub fn with_policy(mut files: Vec<ReportedFile>, policy: &KeeperPolicy) -> DuplicateGroup {
+        policy.rank(&mut files);
+        let keeper = files
+            .first()
+            .map(|file| file.path.clone())
+            .unwrap_or_default();
+
+        DuplicateGroup { keeper, files }
+    }
+
+    /// Returns the distinct roots of the group's files, in keeper order.
+    pub fn roots(&self) -> Vec<&Path> {
+        let mut roots: Vec<&Path> = Vec::new();
+        for root in self.files.iter().filter_map(|file| file.root.as_deref()) {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+
+    /// Returns the files other than the keeper.
+    pub fn duplicates(&self) -> impl Iterator<Item = &ReportedFile> {
+        self.files.iter().filter(|file| file.path != self.keeper)
+    }
+}
+
+/// Returns the deepest of `roots` containing `path`, if any.
+pub fn root_of(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    roots
+        .iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .cloned()
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keeper::KeeperRule;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_keeper_is_highest_resolution() {
+        let group = DuplicateGroup::new(vec![
+            file("small.jpg", 100, Some((250, 375))),
+            file("large.jpg", 50, Some((2520, 3776))),
+            file("unknown.jpg", 500, None),
+        ]);
+
+        assert_eq!(group.keeper, PathBuf::from("large.jpg"));
+        assert_eq!(group.duplicates().count(), 2);
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let mut report = DuplicateReport::new(vec![DuplicateGroup::new(vec![
+            file("small.jpg", 100, Some((250, 375))),
+            file("large.jpg", 50, Some((2520, 3776))),
+        ])]);
+        assert_eq!(report.groups[0].keeper, PathBuf::from("large.jpg"));
+
+        let policy = KeeperPolicy {
+            rules: vec![KeeperRule::FileSize],
+            ..KeeperPolicy::default()
+        };
+        report.apply_policy(&policy);
+
+        assert_eq!(report.groups[0].keeper, PathBuf::from("small.jpg"));
+        assert_eq!(
+            report.paths,
+            vec![vec![PathBuf::from("small.jpg"), PathBuf::from("large.jpg")]]
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let report = DuplicateReport::new(vec![DuplicateGroup::new(vec![
+            file("a.jpg", 100, Some((4, 3))),
+            file("b.jpg", 100, Some((4, 3))),
+        ])]);
+        assert_eq!(
+            report.paths,
+            vec![vec![PathBuf::from("a.jpg"), PathBuf::from("b.jpg")]]
+        );
+
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("report.json");
+        report.save(&path).unwrap();
+        assert_eq!(DuplicateReport::load(&path).unwrap(), report);
+    }
+
+    #[test]
+    fn test_tag_roots() {
+        let mut report = DuplicateReport::new(vec![
+            DuplicateGroup::new(vec![
+                file("/ssd/photos/a.jpg", 100, Some((4, 3))),
+                file("/nas/photos/a.jpg", 100, Some((4, 3))),
+                file("/nas/photos/archive/a.jpg", 100, Some((4, 3))),
+            ]),
+            DuplicateGroup::new(vec![
+                file("/ssd/photos/b.jpg", 100, Some((4, 3))),
+                file("/ssd/photos/c.jpg", 100, Some((4, 3))),
+            ]),
+        ]);
+        let roots = [
+            PathBuf::from("/ssd/photos"),
+            PathBuf::from("/nas/photos"),
+            PathBuf::from("/nas/photos/archive"),
+        ];
+        report.tag_roots(&roots);
+
+        let group = &report.groups[0];
+        assert_eq!(group.roots().len(), 3);
+        let root_of = |name: &str| {
+            group
+                .files
+                .iter()
+                .find(|file| file.path == Path::new(name))
+                .and_then(|file| file.root.clone())
+        };
+        assert_eq!(root_of("/nas/photos/a.jpg"), Some(roots[1].clone()));
+        assert_eq!(root_of("/nas/photos/archive/a.jpg"), Some(roots[2].clone()));
+        assert_eq!(report.groups[1].roots(), vec![roots[0].as_path()]);
+        assert_eq!(report.cross_root_groups(), 1);
+    }
+
+    #[test]
+    fn test_load_paths_only() {
+        let report = DuplicateReport::load(Path::new("09_bak.json")).unwrap();
+
+        assert_eq!(report.groups.len(), report.paths.len());
+        assert!(report.groups[0].files[0]
+            .path
+            .ends_with("DSC_3837/2010-09-02-1.tiff"));
+        assert_eq!(report.groups[0].files[0].resolution, None);
+    }
+
+    fn file(path: &str, size: u64, resolution: Option<(u32, u32)>) -> ReportedFile {
+        ReportedFile {
+            path: PathBuf::from(path),
+            size,
+            resolution,
+            score: Some(1.0),
+            date_time_original: None,
+            sidecar: false,
+            companions: Vec::new(),
+            motion: false,
+            root: None,
+        }
+    }
+}
@@ -1,16 +1,66 @@
 //! Structural Similarity Index (SSIM) implementation.
 
+use std::str::FromStr;
+
 use image::imageops::FilterType;
-use image::DynamicImage;
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
 
 use crate::error::AppError;
 use crate::image::Image;
 
 const ASPECT_RATIO_TOLERANCE: f32 = 0.01;
 
+/// Side of the Gaussian window used by windowed SSIM.
+const WINDOW_SIZE: usize = 11;
+
+/// Standard deviation of the Gaussian window.
+const WINDOW_SIGMA: f32 = 1.5;
+
+/// Relative importance of each scale in MS-SSIM, finest first (Wang et al. 2003).
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// A per-pixel SSIM map.
+pub type SsimMap = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// How the similarity of two images is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SsimMetric {
+    /// Mean, variance and covariance over the whole image.
+    #[default]
+    Global,
+    /// Mean SSIM over 11x11 Gaussian windows.
+    Gaussian,
+    /// Multi-scale SSIM over up to five dyadic scales.
+    MultiScale,
+}
+
+impl FromStr for SsimMetric {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "global" => Ok(SsimMetric::Global),
+            "gaussian" | "ssim" => Ok(SsimMetric::Gaussian),
+            "ms-ssim" | "multiscale" => Ok(SsimMetric::MultiScale),
+            _ => Err(AppError::UnknownMetric(s.to_string())),
+        }
+    }
+}
+
+/// The result of comparing two images.
+#[derive(Debug, Clone)]
+pub struct SsimResult {
+    /// Mean similarity, 1.0 for identical images.
+    pub score: f64,
+    /// Per-pixel SSIM, if requested. Windowed metrics only produce values where the window fits
+    /// inside the image, so the map is `WINDOW_SIZE - 1` pixels narrower and shorter than the
+    /// compared images. The global metric has no map.
+    pub map: Option<SsimMap>,
+}
+
 /// Compute the structural similarity index (SSIM) between two images.
 pub fn ssim_index2(img1: &DynamicImage, img2: &DynamicImage) -> Result<f32, AppError> {
-    let (image1, image2) = normalize_images(&img1, &img2);
+    let (image1, image2) = normalize_images(img1, img2);
 
     // Compute mean intensity of the greyscale images
     let gray1 = image1.to_luma8();
@@ -51,6 +101,55 @@ pub fn ssim_index(img1: &Image, img2: &Image) -> Result<f64, AppError> {
     Ok(ssim_score)
 }
 
+/// Compute the similarity of two images with the given metric, rejecting images with different
+/// aspect ratios.
+pub fn ssim_index_with_metric(
+    img1: &Image,
+    img2: &Image,
+    metric: SsimMetric,
+    with_map: bool,
+) -> Result<SsimResult, AppError> {
+    if (img1.aspect_ratio()? - img2.aspect_ratio()?).abs() > ASPECT_RATIO_TOLERANCE {
+        return Err(AppError::DifferentAspectRatio);
+    }
+
+    compute_ssim(&img1.image()?, &img2.image()?, metric, with_map)
+}
+
+/// Compute the similarity of two images with the given metric, optionally keeping the SSIM map.
+pub fn compute_ssim(
+    img1: &DynamicImage,
+    img2: &DynamicImage,
+    metric: SsimMetric,
+    with_map: bool,
+) -> Result<SsimResult, AppError> {
+    let (image1, image2) = normalize_images(img1, img2);
+    let gray1 = image1.to_luma8();
+    let gray2 = image2.to_luma8();
+
+    match metric {
+        SsimMetric::Global => {
+            let mu1 = mean_intensity(&gray1);
+            let mu2 = mean_intensity(&gray2);
+            let (var1, var2, cov) = variance_covariance(&gray1, &gray2, mu1, mu2);
+
+            Ok(SsimResult {
+                score: ssim(mu1, mu2, var1, var2, cov),
+                map: None,
+            })
+        }
+        SsimMetric::Gaussian => {
+            let maps = windowed_ssim(&Plane::from_image(&gray1), &Plane::from_image(&gray2))?;
+
+            Ok(SsimResult {
+                score: maps.ssim.mean(),
+                map: with_map.then(|| maps.ssim.into_map()),
+            })
+        }
+        SsimMetric::MultiScale => multi_scale_ssim(&gray1, &gray2, with_map),
+    }
+}
+
 // Ensure that the images have the same dimensions
 fn normalize_images(img1: &DynamicImage, img2: &DynamicImage) -> (DynamicImage, DynamicImage) {
     let target_width = img1.width().min(img2.width());
@@ -99,6 +198,193 @@ fn ssim(mu1: f64, mu2: f64, var1: f64, var2: f64, cov: f64) -> f64 {
     numerator / denominator
 }
 
+// A greyscale image with floating point samples
+#[derive(Debug, Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn from_image(img: &GrayImage) -> Plane {
+        Plane {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            data: img.pixels().map(|p| p.0[0] as f32).collect(),
+        }
+    }
+
+    fn map(&self, other: &Plane, f: impl Fn(f32, f32) -> f32) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        self.data.iter().map(|&v| v as f64).sum::<f64>() / self.data.len() as f64
+    }
+
+    // Convolve with a separable kernel, keeping only pixels where the kernel fits the image
+    fn convolve_valid(&self, kernel: &[f32]) -> Plane {
+        let k = kernel.len();
+        let width = self.width - k + 1;
+        let height = self.height - k + 1;
+
+        let mut rows = vec![0.0; width * self.height];
+        for y in 0..self.height {
+            let row = &self.data[y * self.width..(y + 1) * self.width];
+            for x in 0..width {
+                rows[y * width + x] = kernel.iter().zip(&row[x..x + k]).map(|(a, b)| a * b).sum();
+            }
+        }
+
+        let mut data = vec![0.0; width * height];
+        for y in 0..height {
+            for (i, weight) in kernel.iter().enumerate() {
+                let row = &rows[(y + i) * width..(y + i + 1) * width];
+                for (out, value) in data[y * width..(y + 1) * width].iter_mut().zip(row) {
+                    *out += weight * value;
+                }
+            }
+        }
+
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+
+    // Halve the resolution by averaging 2x2 blocks
+    fn downsample(&self) -> Plane {
+        let width = self.width / 2;
+        let height = self.height / 2;
+        let at = |x: usize, y: usize| self.data[y * self.width + x];
+
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let sum = at(2 * x, 2 * y)
+                    + at(2 * x + 1, 2 * y)
+                    + at(2 * x, 2 * y + 1)
+                    + at(2 * x + 1, 2 * y + 1);
+                data.push(sum / 4.0);
+            }
+        }
+
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn into_map(self) -> SsimMap {
+        SsimMap::from_raw(self.width as u32, self.height as u32, self.data)
+            .expect("plane dimensions match its data")
+    }
+}
+
+struct SsimMaps {
+    // Luminance, contrast and structure combined
+    ssim: Plane,
+    // Contrast and structure only
+    cs: Plane,
+}
+
+fn gaussian_kernel() -> Vec<f32> {
+    let centre = (WINDOW_SIZE / 2) as f32;
+    let kernel: Vec<f32> = (0..WINDOW_SIZE)
+        .map(|i| (-(i as f32 - centre).powi(2) / (2.0 * WINDOW_SIGMA.powi(2))).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter().map(|v| v / sum).collect()
+}
+
+fn windowed_ssim(x: &Plane, y: &Plane) -> Result<SsimMaps, AppError> {
+    if x.width < WINDOW_SIZE || x.height < WINDOW_SIZE {
+        return Err(AppError::ImageTooSmall(x.width as u32, x.height as u32));
+    }
+
+    let c1 = (0.01f32 * 255.0).powi(2);
+    let c2 = (0.03f32 * 255.0).powi(2);
+    let kernel = gaussian_kernel();
+
+    let mu_x = x.convolve_valid(&kernel);
+    let mu_y = y.convolve_valid(&kernel);
+    let xx = x.map(x, |a, b| a * b).convolve_valid(&kernel);
+    let yy = y.map(y, |a, b| a * b).convolve_valid(&kernel);
+    let xy = x.map(y, |a, b| a * b).convolve_valid(&kernel);
+
+    let mut ssim = mu_x.clone();
+    let mut cs = mu_x.clone();
+    for i in 0..mu_x.data.len() {
+        let (mx, my) = (mu_x.data[i], mu_y.data[i]);
+        let var_x = xx.data[i] - mx * mx;
+        let var_y = yy.data[i] - my * my;
+        let cov = xy.data[i] - mx * my;
+
+        let contrast_structure = (2.0 * cov + c2) / (var_x + var_y + c2);
+        let luminance = (2.0 * mx * my + c1) / (mx * mx + my * my + c1);
+
+        cs.data[i] = contrast_structure;
+        ssim.data[i] = luminance * contrast_structure;
+    }
+
+    Ok(SsimMaps { ssim, cs })
+}
+
+fn multi_scale_ssim(
+    gray1: &GrayImage,
+    gray2: &GrayImage,
+    with_map: bool,
+) -> Result<SsimResult, AppError> {
+    let mut x = Plane::from_image(gray1);
+    let mut y = Plane::from_image(gray2);
+
+    // Small images can't be downsampled five times, so use as many scales as fit and
+    // renormalise their weights
+    let mut scales = 1;
+    let mut side = x.width.min(x.height);
+    while scales < MS_SSIM_WEIGHTS.len() && side / 2 >= WINDOW_SIZE {
+        scales += 1;
+        side /= 2;
+    }
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let total: f64 = weights.iter().sum();
+
+    let mut score = 1.0;
+    let mut map = None;
+    for (scale, weight) in weights.iter().enumerate() {
+        let maps = windowed_ssim(&x, &y)?;
+
+        // Negative values can't be raised to a fractional power
+        let value = if scale == scales - 1 {
+            maps.ssim.mean()
+        } else {
+            maps.cs.mean()
+        };
+        score *= value.max(0.0).powf(weight / total);
+
+        if scale == 0 && with_map {
+            map = Some(maps.ssim.into_map());
+        }
+
+        x = x.downsample();
+        y = y.downsample();
+    }
+
+    Ok(SsimResult { score, map })
+}
+
 // tests ------------------------------------------------------
 
 #[cfg(test)]
@@ -158,6 +444,73 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_windowed_metrics_identical() {
+        let img = pattern(128, 96, 0);
+
+        for metric in [SsimMetric::Gaussian, SsimMetric::MultiScale] {
+            let result = compute_ssim(&img, &img, metric, true).unwrap();
+            assert!((result.score - 1.0).abs() < 1e-6, "{:?}", metric);
+
+            let map = result.map.unwrap();
+            assert_eq!(map.dimensions(), (118, 86));
+            assert!(map.pixels().all(|p| (p.0[0] - 1.0).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_windowed_metrics_detect_local_change() {
+        let img1 = pattern(128, 96, 0);
+        let img2 = pattern(128, 96, 40);
+
+        let global = compute_ssim(&img1, &img2, SsimMetric::Global, true).unwrap();
+        let gaussian = compute_ssim(&img1, &img2, SsimMetric::Gaussian, true).unwrap();
+        let multi_scale = compute_ssim(&img1, &img2, SsimMetric::MultiScale, false).unwrap();
+
+        assert!(global.map.is_none());
+        assert!(multi_scale.map.is_none());
+        assert!(gaussian.score < 1.0);
+        assert!(multi_scale.score < 1.0);
+
+        // The map pinpoints the changed block in the bottom right corner
+        let map = gaussian.map.unwrap();
+        assert!(map.get_pixel(10, 10).0[0] > 0.99);
+        assert!(map.get_pixel(100, 70).0[0] < 0.5);
+    }
+
+    #[test]
+    fn test_windowed_ssim_too_small() {
+        let img = pattern(8, 8, 0);
+        let result = compute_ssim(&img, &img, SsimMetric::Gaussian, false);
+        assert!(matches!(result, Err(AppError::ImageTooSmall(8, 8))));
+    }
+
+    #[test]
+    fn test_metric_from_str() {
+        assert_eq!(
+            "ms-ssim".parse::<SsimMetric>().unwrap(),
+            SsimMetric::MultiScale
+        );
+        assert_eq!(
+            "Gaussian".parse::<SsimMetric>().unwrap(),
+            SsimMetric::Gaussian
+        );
+        assert!("psnr".parse::<SsimMetric>().is_err());
+    }
+
+    // Noise-like texture, with the bottom right quarter inverted by `shift`
+    fn pattern(width: u32, height: u32, shift: u8) -> DynamicImage {
+        let img = GrayImage::from_fn(width, height, |x, y| {
+            let v = ((x * 37 + y * 91 + (x * y) % 7 * 13) % 256) as u8;
+            if x >= width * 3 / 4 && y >= height * 3 / 4 && shift > 0 {
+                Luma([v.wrapping_add(128).wrapping_add(shift)])
+            } else {
+                Luma([v])
+            }
+        });
+        DynamicImage::ImageLuma8(img)
+    }
+
     fn get_test_img(path: &str) -> Result<Image, AppError> {
         let project_root = std::path::PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
         let image_path = project_root.join(format!("test-data/{}", path));
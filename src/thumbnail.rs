@@ -0,0 +1,178 @@
+//! Bounded cache of the images compared during a scan.
+//! A 4032×3024 photo takes 36 MB decoded, so rather than full images the cache holds grayscale
+//! thumbnails, which is all the SSIM comparison looks at, and evicts the least recently used once
+//! they exceed a number of bytes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::error::AppError;
+use crate::image::Image;
+
+/// Longest side of a thumbnail. Leaves enough pixels for the five scales of MS-SSIM.
+pub const THUMBNAIL_SIZE: u32 = 512;
+
+/// Returns the grayscale thumbnail compared in place of an oriented image. Images no larger than
+/// `THUMBNAIL_SIZE` keep their size.
+pub fn thumbnail(img: &DynamicImage) -> DynamicImage {
+    let img = if img.width().max(img.height()) > THUMBNAIL_SIZE {
+        img.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+    } else {
+        img.clone()
+    };
+
+    DynamicImage::ImageLuma8(img.to_luma8())
+}
+
+/// Thumbnails by path, least recently used first out. Shared between the threads comparing
+/// images.
+pub struct ThumbnailCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Each thumbnail with the time it was last used.
+    thumbnails: HashMap<PathBuf, (Arc<DynamicImage>, u64)>,
+    /// Paths by the time they were last used.
+    used: BTreeMap<u64, PathBuf>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl ThumbnailCache {
+    /// A cache holding at most `capacity` bytes of thumbnails, or a single one if it is larger.
+    pub fn new(capacity: usize) -> ThumbnailCache {
+        ThumbnailCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Returns the thumbnail of an image, decoding it if it isn't cached.
+    pub fn get(&self, path: &Path) -> Result<Arc<DynamicImage>, AppError> {
+        if let Some(thumbnail) = self.lru.lock().unwrap().touch(path) {
+            return Ok(thumbnail);
+        }
+
+        // Decode without holding the lock so that other threads can load their images meanwhile
+        let decoded = Image::from_path(&path.to_path_buf())?.image()?;
+        let thumbnail = Arc::new(thumbnail(&decoded));
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.insert(path, thumbnail.clone());
+        while lru.bytes > self.capacity && lru.thumbnails.len() > 1 {
+            lru.evict();
+        }
+
+        Ok(thumbnail)
+    }
+
+    /// Number of thumbnails held.
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().thumbnails.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the thumbnails held.
+    pub fn bytes(&self) -> usize {
+        self.lru.lock().unwrap().bytes
+    }
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn touch(&mut self, path: &Path) -> Option<Arc<DynamicImage>> {
+        let now = self.tick();
+        let (thumbnail, used) = self.thumbnails.get_mut(path)?;
+        let path = self.used.remove(used)?;
+        *used = now;
+        self.used.insert(now, path);
+
+        Some(thumbnail.clone())
+    }
+
+    fn insert(&mut self, path: &Path, thumbnail: Arc<DynamicImage>) {
+        // Another thread may have loaded the same image meanwhile
+        if self.touch(path).is_some() {
+            return;
+        }
+
+        let now = self.tick();
+        self.bytes += thumbnail.as_bytes().len();
+        self.thumbnails.insert(path.to_path_buf(), (thumbnail, now));
+        self.used.insert(now, path.to_path_buf());
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, path)) = self.used.pop_first() {
+            if let Some((thumbnail, _)) = self.thumbnails.remove(&path) {
+                self.bytes -= thumbnail.as_bytes().len();
+            }
+        }
+    }
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail() {
+        let img = DynamicImage::new_rgb8(4032, 3024);
+        let small = thumbnail(&img);
+        assert_eq!((small.width(), small.height()), (512, 384));
+        assert_eq!(small.as_bytes().len(), 512 * 384);
+
+        let img = DynamicImage::new_rgb8(250, 375);
+        assert_eq!(thumbnail(&img).width(), 250);
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let paths: Vec<PathBuf> = [
+            "coffee-small.jpg",
+            "face-right-1-small.jpg",
+            "face-left.jpg",
+        ]
+        .iter()
+        .map(|name| PathBuf::from("test-data/02").join(name))
+        .collect();
+
+        let unbounded = ThumbnailCache::new(usize::MAX);
+        let sizes: Vec<usize> = paths
+            .iter()
+            .map(|path| unbounded.get(path).unwrap().as_bytes().len())
+            .collect();
+        assert_eq!(unbounded.len(), 3);
+        assert_eq!(unbounded.bytes(), sizes.iter().sum::<usize>());
+
+        // Room for the first thumbnail and either of the others
+        let cache = ThumbnailCache::new(sizes[0] + sizes[1].max(sizes[2]));
+        cache.get(&paths[0]).unwrap();
+        cache.get(&paths[1]).unwrap();
+        cache.get(&paths[0]).unwrap();
+        cache.get(&paths[2]).unwrap();
+
+        // The second was used least recently
+        let lru = cache.lru.lock().unwrap();
+        assert!(lru.thumbnails.contains_key(&paths[0]));
+        assert!(!lru.thumbnails.contains_key(&paths[1]));
+        assert!(lru.thumbnails.contains_key(&paths[2]));
+        assert_eq!(lru.bytes, sizes[0] + sizes[2]);
+    }
+}
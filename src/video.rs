@@ -0,0 +1,469 @@
+//! Video files.
+//! Container metadata is read from the MP4/QuickTime atoms directly. Frames are decoded by running
+//! `ffmpeg`, which has to be on the path for re-encoded duplicates to be found; without it only
+//! identical video files are detected.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::DateTime;
+use image::{DynamicImage, ImageFormat};
+
+use crate::error::AppError;
+use crate::fingerprint::{Fingerprints, PerceptualHash};
+
+pub const EXTENSIONS: [&str; 3] = ["mov", "mp4", "m4v"];
+
+/// Number of frames fingerprinted, spread evenly over the video.
+pub const FRAME_COUNT: usize = 5;
+
+/// Metadata key of the identifier shared by the still and video of a Live Photo.
+const CONTENT_IDENTIFIER_KEY: &[u8] = b"com.apple.quicktime.content.identifier";
+
+/// Seconds between the QuickTime epoch, 1904-01-01, and the Unix epoch.
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Returns true if the file has a video extension.
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Returns true if `ffmpeg` can be run.
+pub fn ffmpeg_available() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoMetadata {
+    /// Length in seconds.
+    pub duration: f64,
+    /// Resolution of the first video track after applying its rotation.
+    pub resolution: (u32, u32),
+    /// Creation date in the EXIF format, e.g. "2024:05:01 12:00:00", in UTC.
+    pub creation_date: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Video {
+    pub path: PathBuf,
+}
+
+impl Video {
+    pub fn from_path(path: &Path) -> Video {
+        Video {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Read the duration, resolution and creation date from the `moov` atom.
+    pub fn metadata(&self) -> Result<VideoMetadata, AppError> {
+        let moov = read_moov(&self.path)?;
+        let children = atoms(&moov)?;
+
+        let mvhd = find(&children, b"mvhd")
+            .ok_or_else(|| AppError::InvalidVideo("no movie header".to_string()))?;
+        let (creation_time, timescale, duration) = movie_header(mvhd)?;
+        if timescale == 0 {
+            return Err(AppError::InvalidVideo("zero timescale".to_string()));
+        }
+
+        let mut resolution = None;
+        for (_, trak) in children.iter().filter(|(kind, _)| kind == b"trak") {
+            let Some(tkhd) = find(&atoms(trak)?, b"tkhd") else {
+                continue;
+            };
+            // Audio tracks have no size
+            if let Some(size) = track_size(tkhd)?.filter(|&(w, h)| w > 0 && h > 0) {
+                resolution = Some(size);
+                break;
+            }
+        }
+
+        Ok(VideoMetadata {
+            duration: duration as f64 / timescale as f64,
+            resolution: resolution
+                .ok_or_else(|| AppError::InvalidVideo("no video track".to_string()))?,
+            creation_date: (creation_time > 0)
+                .then(|| DateTime::from_timestamp(creation_time as i64 - QUICKTIME_EPOCH_OFFSET, 0))
+                .flatten()
+                .map(|date| date.format("%Y:%m:%d %H:%M:%S").to_string()),
+        })
+    }
+
+    /// Returns the identifier that links the video of a Live Photo to its still, stored under the
+    /// `com.apple.quicktime.content.identifier` key of the movie metadata.
+    pub fn content_identifier(&self) -> Result<Option<String>, AppError> {
+        let moov = read_moov(&self.path)?;
+        let Some(meta) = find(&atoms(&moov)?, b"meta") else {
+            return Ok(None);
+        };
+        // QuickTime metadata atoms hold their children directly, MP4 ones after a version and flags
+        let children = match atoms(meta) {
+            Ok(children) if find(&children, b"keys").is_some() => children,
+            _ => atoms(meta.get(4..).unwrap_or_default())?,
+        };
+        let (Some(keys), Some(ilst)) = (find(&children, b"keys"), find(&children, b"ilst")) else {
+            return Ok(None);
+        };
+
+        // Keys are numbered from one, after a version, flags and count
+        let Some(index) = atoms(keys.get(8..).unwrap_or_default())?
+            .iter()
+            .position(|(_, key)| *key == CONTENT_IDENTIFIER_KEY)
+        else {
+            return Ok(None);
+        };
+        let index = (index as u32 + 1).to_be_bytes();
+
+        // The value follows a type indicator and locale
+        Ok(atoms(ilst)?
+            .iter()
+            .find(|(kind, _)| *kind == index)
+            .and_then(|(_, item)| find(&atoms(item).ok()?, b"data"))
+            .and_then(|data| data.get(8..))
+            .map(|value| String::from_utf8_lossy(value).into_owned()))
+    }
+
+    /// Decode the frame at `seconds` from the start, rotated as it is displayed.
+    pub fn frame(&self, seconds: f64) -> Result<DynamicImage, AppError> {
+        let output = Command::new("ffmpeg")
+            .args(["-v", "error", "-ss", &format!("{:.3}", seconds), "-i"])
+            .arg(&self.path)
+            .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+            .output()
+            .map_err(|e| AppError::FfmpegError(e.to_string()))?;
+
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(AppError::FfmpegError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        Ok(image::load_from_memory_with_format(
+            &output.stdout,
+            ImageFormat::Png,
+        )?)
+    }
+
+    /// Perceptual hashes of `FRAME_COUNT` frames taken at the same relative positions in every
+    /// video, so that the fingerprints of re-encoded copies line up.
+    pub fn fingerprints(&self, duration: f64) -> Result<Vec<Fingerprints>, AppError> {
+        // The middle of each slice avoids black first and last frames
+        (0..FRAME_COUNT)
+            .map(|i| {
+                let seconds = duration * (i as f64 + 0.5) / FRAME_COUNT as f64;
+                self.frame(seconds)
+                    .map(|frame| Fingerprints::from_image(&frame))
+            })
+            .collect()
+    }
+}
+
+/// Mean Hamming distance between the frame fingerprints of two videos, or None if they don't
+/// have the same number of frames.
+pub fn fingerprint_distance(a: &[PerceptualHash], b: &[PerceptualHash]) -> Option<f32> {
+    if a.is_empty() || a.len() != b.len() {
+        return None;
+    }
+    let total: u32 = a.iter().zip(b).map(|(a, b)| a.distance(b)).sum();
+
+    Some(total as f32 / a.len() as f32)
+}
+
+// The body of the top-level moov atom, which may come before or after the media data
+fn read_moov(path: &Path) -> Result<Vec<u8>, AppError> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut offset = 0;
+    while offset + 8 <= len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The size follows the type as 64 bits
+            1 => {
+                let mut size = [0u8; 8];
+                file.read_exact(&mut size)?;
+                (u64::from_be_bytes(size), 16)
+            }
+            // The atom extends to the end of the file
+            0 => (len - offset, 8),
+            size => (size as u64, 8),
+        };
+        if size < header_len || offset + size > len {
+            return Err(AppError::InvalidVideo("truncated atom".to_string()));
+        }
+
+        if &header[4..] == b"moov" {
+            let mut body = vec![0u8; (size - header_len) as usize];
+            file.read_exact(&mut body)?;
+            return Ok(body);
+        }
+        offset += size;
+    }
+
+    Err(AppError::InvalidVideo("no movie atom".to_string()))
+}
+
+// The type and body of an atom
+type Atom<'a> = ([u8; 4], &'a [u8]);
+
+// The atoms in `data`
+fn atoms(data: &[u8]) -> Result<Vec<Atom<'_>>, AppError> {
+    let truncated = || AppError::InvalidVideo("truncated atom".to_string());
+
+    let mut atoms = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let kind: [u8; 4] = rest[4..8].try_into().unwrap();
+        let (size, header_len) = match be_u32(rest, 0)? {
+            1 => (be_u64(rest, 8)? as usize, 16),
+            0 => (rest.len(), 8),
+            size => (size as usize, 8),
+        };
+        if size < header_len || size > rest.len() {
+            return Err(truncated());
+        }
+        atoms.push((kind, &rest[header_len..size]));
+        rest = &rest[size..];
+    }
+
+    Ok(atoms)
+}
+
+fn find<'a>(atoms: &[Atom<'a>], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    atoms.iter().find(|(k, _)| k == kind).map(|(_, body)| *body)
+}
+
+// Creation time, timescale and duration
+fn movie_header(mvhd: &[u8]) -> Result<(u64, u32, u64), AppError> {
+    match mvhd.first() {
+        Some(0) => Ok((
+            be_u32(mvhd, 4)? as u64,
+            be_u32(mvhd, 12)?,
+            be_u32(mvhd, 16)? as u64,
+        )),
+        Some(1) => Ok((be_u64(mvhd, 4)?, be_u32(mvhd, 20)?, be_u64(mvhd, 24)?)),
+        _ => Err(AppError::InvalidVideo(
+            "unknown movie header version".to_string(),
+        )),
+    }
+}
+
+// Width and height of a track, swapped when it is displayed rotated by 90 or 270 degrees
+fn track_size(tkhd: &[u8]) -> Result<Option<(u32, u32)>, AppError> {
+    let matrix = match tkhd.first() {
+        Some(0) => 40,
+        Some(1) => 52,
+        _ => return Ok(None),
+    };
+    let width = be_u32(tkhd, matrix + 36)? >> 16;
+    let height = be_u32(tkhd, matrix + 40)? >> 16;
+
+    // The matrix is [a b u, c d v, x y w]; a rotation by a quarter turn zeroes a and d
+    let (a, d) = (be_u32(tkhd, matrix)?, be_u32(tkhd, matrix + 16)?);
+    if a == 0 && d == 0 {
+        Ok(Some((height, width)))
+    } else {
+        Ok(Some((width, height)))
+    }
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Result<u32, AppError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| AppError::InvalidVideo("truncated atom".to_string()))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> Result<u64, AppError> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| AppError::InvalidVideo("truncated atom".to_string()))
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::fingerprint::HashAlgorithm;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_metadata() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+
+        let path = dir.join("clip.mp4");
+        fs::write(&path, mp4(12.5, (1920, 1080), false)).unwrap();
+        let metadata = Video::from_path(&path).metadata().unwrap();
+        assert_eq!(metadata.duration, 12.5);
+        assert_eq!(metadata.resolution, (1920, 1080));
+        assert_eq!(
+            metadata.creation_date.as_deref(),
+            Some("2024:05:01 12:00:00")
+        );
+
+        // Portrait phone videos are stored landscape with a rotation
+        let rotated = dir.join("IMG_0001.MOV");
+        fs::write(&rotated, mp4(3.0, (1920, 1080), true)).unwrap();
+        assert!(is_video(&rotated));
+        let metadata = Video::from_path(&rotated).metadata().unwrap();
+        assert_eq!(metadata.resolution, (1080, 1920));
+
+        let live = dir.join("IMG_0002.MOV");
+        fs::write(
+            &live,
+            live_photo_mov("A1B2C3D4-0000-4000-8000-000000000001"),
+        )
+        .unwrap();
+        assert_eq!(
+            Video::from_path(&live)
+                .content_identifier()
+                .unwrap()
+                .as_deref(),
+            Some("A1B2C3D4-0000-4000-8000-000000000001")
+        );
+        assert_eq!(Video::from_path(&path).content_identifier().unwrap(), None);
+
+        let image = PathBuf::from("test-data/orientation/orientation-1.jpg");
+        assert!(!is_video(&image));
+        assert!(Video::from_path(&image).metadata().is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_distance() {
+        let a = [PerceptualHash(0), PerceptualHash(0xff)];
+        let b = [PerceptualHash(0b11), PerceptualHash(0xff)];
+        assert_eq!(fingerprint_distance(&a, &b), Some(1.0));
+        assert_eq!(fingerprint_distance(&a, &a), Some(0.0));
+        assert_eq!(fingerprint_distance(&a, &b[..1]), None);
+        assert_eq!(fingerprint_distance(&[], &[]), None);
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn test_frame_fingerprints() {
+        let temp = TempDir::new().unwrap();
+        let clip = encode(temp.path(), "clip.mp4", "testsrc=size=640x360", None);
+        let smaller = encode(
+            temp.path(),
+            "smaller.mp4",
+            "testsrc=size=640x360",
+            Some(320),
+        );
+        let other = encode(temp.path(), "other.mp4", "mandelbrot=size=640x360", None);
+
+        let video = Video::from_path(&clip);
+        let frame = video.frame(1.0).unwrap();
+        assert_eq!((frame.width(), frame.height()), (640, 360));
+        assert!(video.frame(60.0).is_err());
+
+        let fingerprints = |path: &Path| {
+            let video = Video::from_path(path);
+            let duration = video.metadata().unwrap().duration;
+            let fingerprints = video.fingerprints(duration).unwrap();
+            fingerprints
+                .iter()
+                .map(|frame| frame.get(HashAlgorithm::Dct))
+                .collect::<Vec<_>>()
+        };
+        let (clip, smaller, other) = (
+            fingerprints(&clip),
+            fingerprints(&smaller),
+            fingerprints(&other),
+        );
+        assert_eq!(clip.len(), FRAME_COUNT);
+        assert!(fingerprint_distance(&clip, &smaller).unwrap() <= 4.0);
+        assert!(fingerprint_distance(&clip, &other).unwrap() > 10.0);
+    }
+
+    /// Encode four seconds of an ffmpeg test source, optionally scaled to `width`.
+    pub(crate) fn encode(dir: &Path, name: &str, source: &str, width: Option<u32>) -> PathBuf {
+        let path = dir.join(name);
+        let mut command = Command::new("ffmpeg");
+        command
+            .args(["-v", "error", "-f", "lavfi", "-i", source, "-t", "4"])
+            .args(["-pix_fmt", "yuv420p"]);
+        if let Some(width) = width {
+            command.args(["-vf", &format!("scale={}:-2", width)]);
+        }
+        assert!(command.arg(&path).status().unwrap().success());
+        path
+    }
+
+    /// A QuickTime file with a movie header, an audio track and a video track, but no media data.
+    /// The creation date is 2024-05-01 12:00:00 UTC.
+    pub(crate) fn mp4(duration: f64, size: (u32, u32), rotated: bool) -> Vec<u8> {
+        movie(duration, size, rotated, &[])
+    }
+
+    /// The video of a Live Photo, with QuickTime metadata holding its content identifier.
+    pub(crate) fn live_photo_mov(identifier: &str) -> Vec<u8> {
+        let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        keys.extend(atom(b"mdta", b"com.apple.quicktime.make"));
+        keys.extend(atom(b"mdta", CONTENT_IDENTIFIER_KEY));
+
+        let mut ilst = Vec::new();
+        for (index, value) in [(1u32, "Apple"), (2, identifier)] {
+            let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+            data.extend(value.as_bytes());
+            ilst.extend(atom(&index.to_be_bytes(), &atom(b"data", &data)));
+        }
+
+        let mut meta = atom(b"hdlr", &[0u8; 24]);
+        meta.extend(atom(b"keys", &keys));
+        meta.extend(atom(b"ilst", &ilst));
+        movie(3.0, (1920, 1440), false, &atom(b"meta", &meta))
+    }
+
+    fn movie(duration: f64, (width, height): (u32, u32), rotated: bool, extra: &[u8]) -> Vec<u8> {
+        let creation_time = (1_714_564_800 + QUICKTIME_EPOCH_OFFSET) as u32;
+
+        let mut mvhd = vec![0u8; 4];
+        for value in [creation_time, creation_time, 600, (duration * 600.0) as u32] {
+            mvhd.extend(value.to_be_bytes());
+        }
+        mvhd.resize(100, 0);
+
+        let track = |size: (u32, u32), rotated: bool| {
+            let mut tkhd = vec![0u8; 40];
+            let matrix: [u32; 9] = if rotated {
+                [0, 0x10000, 0, 0xffff0000, 0, 0, 0, 0, 0x40000000]
+            } else {
+                [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000]
+            };
+            for value in matrix.into_iter().chain([size.0 << 16, size.1 << 16]) {
+                tkhd.extend(value.to_be_bytes());
+            }
+            atom(b"trak", &atom(b"tkhd", &tkhd))
+        };
+
+        let mut moov = atom(b"mvhd", &mvhd);
+        moov.extend(track((0, 0), false));
+        moov.extend(track((width, height), rotated));
+        moov.extend(extra);
+
+        let mut file = atom(b"ftyp", b"isom\0\0\x02\0isomiso2mp41");
+        file.extend(atom(b"mdat", &[0u8; 16]));
+        file.extend(atom(b"moov", &moov));
+        file
+    }
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend(kind);
+        atom.extend(body);
+        atom
+    }
+}
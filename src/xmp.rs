@@ -0,0 +1,502 @@
+//! XMP sidecar files.
+//! Reads and writes the handful of properties that are merged between duplicates: capture date,
+//! camera, location and keywords. Updating a sidecar leaves every other property as it was.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+
+use crate::error::AppError;
+use crate::exif::GpsPosition;
+
+const DATE_TIME_ORIGINAL: &str = "exif:DateTimeOriginal";
+const MAKE: &str = "tiff:Make";
+const MODEL: &str = "tiff:Model";
+const GPS_LATITUDE: &str = "exif:GPSLatitude";
+const GPS_LONGITUDE: &str = "exif:GPSLongitude";
+const SUBJECT: &str = "dc:subject";
+
+/// Properties this module owns when updating a sidecar.
+const MANAGED: [&str; 6] = [
+    DATE_TIME_ORIGINAL,
+    MAKE,
+    MODEL,
+    GPS_LATITUDE,
+    GPS_LONGITUDE,
+    SUBJECT,
+];
+
+/// Other dates that are read when there is no `exif:DateTimeOriginal`.
+const FALLBACK_DATES: [&str; 2] = ["photoshop:DateCreated", "xmp:CreateDate"];
+
+const NAMESPACES: [(&str, &str); 3] = [
+    ("xmlns:exif", "http://ns.adobe.com/exif/1.0/"),
+    ("xmlns:tiff", "http://ns.adobe.com/tiff/1.0/"),
+    ("xmlns:dc", "http://purl.org/dc/elements/1.1/"),
+];
+
+const EMPTY_SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""/>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct XmpMetadata {
+    /// Capture date in XMP format, e.g. `2017-03-12T17:50:35.400`.
+    pub date_time_original: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub gps: Option<GpsPosition>,
+    pub keywords: Vec<String>,
+}
+
+/// Returns the path of the sidecar file of an image.
+pub fn sidecar_path(image: &Path) -> PathBuf {
+    image.with_extension("xmp")
+}
+
+/// Convert an EXIF date such as `2017:03:12 17:50:35` to XMP format.
+pub fn exif_to_xmp_date(date: &str) -> String {
+    match date.split_once(' ') {
+        Some((day, time)) => format!("{}T{}", day.replace(':', "-"), time),
+        None => date.replace(':', "-"),
+    }
+}
+
+// Properties combined once the whole packet has been read, as they may come in any order
+#[derive(Default)]
+struct Pending {
+    fallback_date: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+impl XmpMetadata {
+    pub fn read(path: &Path) -> Result<XmpMetadata, AppError> {
+        XmpMetadata::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(xml: &str) -> Result<XmpMetadata, AppError> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        let mut metadata = XmpMetadata::default();
+        let mut pending = Pending::default();
+        let mut stack: Vec<String> = Vec::new();
+
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) => {
+                    let name = qname(&e);
+                    if is_top_level_description(&name, &stack) {
+                        metadata.read_attributes(&e, &mut pending)?;
+                    }
+                    stack.push(name);
+                }
+                Event::Empty(e) if is_top_level_description(&qname(&e), &stack) => {
+                    metadata.read_attributes(&e, &mut pending)?;
+                }
+                Event::Text(e) => {
+                    let text = e.unescape()?.into_owned();
+                    metadata.read_element(&stack, text, &mut pending);
+                }
+                Event::End(_) => {
+                    stack.pop();
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        metadata.date_time_original = metadata.date_time_original.or(pending.fallback_date);
+        if let (Some(latitude), Some(longitude)) = (pending.latitude, pending.longitude) {
+            metadata.gps = Some(GpsPosition {
+                latitude,
+                longitude,
+            });
+        }
+        Ok(metadata)
+    }
+
+    /// Write the properties to a sidecar, creating it if necessary.
+    pub fn write(&self, path: &Path) -> Result<(), AppError> {
+        let existing = if path.exists() {
+            fs::read_to_string(path)?
+        } else {
+            EMPTY_SIDECAR.to_string()
+        };
+        let updated = self.update(&existing)?;
+
+        // Replace the sidecar in one step so that an interrupted write can't truncate it
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let tmp = path.with_file_name(name);
+        fs::write(&tmp, updated)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Returns `xml` with the properties replaced by these values.
+    pub fn update(&self, xml: &str) -> Result<String, AppError> {
+        let mut reader = Reader::from_str(xml);
+        let mut writer = Writer::new(Vec::new());
+
+        let mut stack: Vec<String> = Vec::new();
+        let mut written = false;
+        // Depth of the managed property being dropped, if any
+        let mut skipping: Option<usize> = None;
+
+        loop {
+            let event = reader.read_event()?;
+            match &event {
+                Event::Eof => break,
+                _ if skipping.is_some() => {
+                    match event {
+                        Event::Start(_) => stack.push(String::new()),
+                        Event::End(_) => {
+                            stack.pop();
+                            if skipping == Some(stack.len()) {
+                                skipping = None;
+                            }
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                Event::Start(e) | Event::Empty(e) => {
+                    let name = qname(e);
+                    let is_empty = matches!(event, Event::Empty(_));
+
+                    if is_managed_property(&name, &stack) {
+                        if !is_empty {
+                            skipping = Some(stack.len());
+                            stack.push(name);
+                        }
+                        continue;
+                    }
+
+                    if is_top_level_description(&name, &stack) {
+                        let start = self.description_start(e, !written)?;
+                        if !written {
+                            written = true;
+                            writer.write_event(Event::Start(start))?;
+                            self.write_keywords(&mut writer)?;
+                            if is_empty {
+                                writer.write_event(Event::End(BytesEnd::new(name)))?;
+                            } else {
+                                stack.push(name);
+                            }
+                        } else if is_empty {
+                            writer.write_event(Event::Empty(start))?;
+                        } else {
+                            writer.write_event(Event::Start(start))?;
+                            stack.push(name);
+                        }
+                        continue;
+                    }
+
+                    if !is_empty {
+                        stack.push(name);
+                    }
+                }
+                Event::End(_) => {
+                    stack.pop();
+                }
+                _ => {}
+            }
+            writer.write_event(event)?;
+        }
+
+        if !written {
+            return Err(AppError::InvalidXmp(
+                "no rdf:Description element".to_string(),
+            ));
+        }
+
+        String::from_utf8(writer.into_inner()).map_err(|e| AppError::InvalidXmp(e.to_string()))
+    }
+
+    fn read_attributes(&mut self, e: &BytesStart, pending: &mut Pending) -> Result<(), AppError> {
+        for attribute in e.attributes() {
+            let attribute = attribute.map_err(|e| AppError::InvalidXmp(e.to_string()))?;
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            let value = attribute.unescape_value()?.into_owned();
+            self.read_property(&key, value, pending);
+        }
+
+        Ok(())
+    }
+
+    // Text inside a property element, or inside a keyword of dc:subject
+    fn read_element(&mut self, stack: &[String], text: String, pending: &mut Pending) {
+        let n = stack.len();
+        if n >= 3 && stack[n - 1] == "rdf:li" && stack[n - 3] == SUBJECT {
+            self.keywords.push(text);
+        } else if n >= 3 && stack[n - 2] == "rdf:Description" && stack[n - 3] == "rdf:RDF" {
+            self.read_property(&stack[n - 1], text, pending);
+        }
+    }
+
+    fn read_property(&mut self, key: &str, value: String, pending: &mut Pending) {
+        match key {
+            DATE_TIME_ORIGINAL => self.date_time_original = Some(value),
+            MAKE => self.make = Some(value),
+            MODEL => self.model = Some(value),
+            GPS_LATITUDE => pending.latitude = parse_coordinate(&value),
+            GPS_LONGITUDE => pending.longitude = parse_coordinate(&value),
+            _ if FALLBACK_DATES.contains(&key) && pending.fallback_date.is_none() => {
+                pending.fallback_date = Some(value);
+            }
+            _ => {}
+        }
+    }
+
+    // The start tag of a top level rdf:Description with the managed attributes replaced. The
+    // values are only written to the first one.
+    fn description_start(
+        &self,
+        e: &BytesStart,
+        with_values: bool,
+    ) -> Result<BytesStart<'static>, AppError> {
+        let mut attributes: Vec<(String, String)> = Vec::new();
+
+        for attribute in e.attributes() {
+            let attribute = attribute.map_err(|e| AppError::InvalidXmp(e.to_string()))?;
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            if MANAGED.contains(&key.as_str()) {
+                continue;
+            }
+            attributes.push((key, attribute.unescape_value()?.into_owned()));
+        }
+
+        if with_values {
+            for (key, uri) in NAMESPACES {
+                if !attributes.iter().any(|(k, _)| k == key) {
+                    attributes.push((key.to_string(), uri.to_string()));
+                }
+            }
+
+            let gps = self.gps.map(|gps| {
+                (
+                    format_coordinate(gps.latitude, 'N', 'S'),
+                    format_coordinate(gps.longitude, 'E', 'W'),
+                )
+            });
+            let values = [
+                (DATE_TIME_ORIGINAL, self.date_time_original.clone()),
+                (MAKE, self.make.clone()),
+                (MODEL, self.model.clone()),
+                (GPS_LATITUDE, gps.as_ref().map(|(lat, _)| lat.clone())),
+                (GPS_LONGITUDE, gps.map(|(_, long)| long)),
+            ];
+            for (key, value) in values {
+                if let Some(value) = value {
+                    attributes.push((key.to_string(), value));
+                }
+            }
+        }
+
+        // One attribute per line, the layout Adobe tools write
+        let name = qname(e);
+        let mut content = name.clone();
+        for (key, value) in attributes {
+            let indent = if key == "rdf:about" {
+                " "
+            } else if key.starts_with("xmlns:") {
+                "\n    "
+            } else {
+                "\n   "
+            };
+            content.push_str(&format!("{}{}=\"{}\"", indent, key, escape(&value)));
+        }
+
+        Ok(BytesStart::from_content(content, name.len()))
+    }
+
+    fn write_keywords(&self, writer: &mut Writer<Vec<u8>>) -> Result<(), AppError> {
+        if self.keywords.is_empty() {
+            return Ok(());
+        }
+
+        writer.write_event(Event::Start(BytesStart::new(SUBJECT)))?;
+        writer.write_event(Event::Start(BytesStart::new("rdf:Bag")))?;
+        for keyword in &self.keywords {
+            writer.write_event(Event::Start(BytesStart::new("rdf:li")))?;
+            writer.write_event(Event::Text(BytesText::new(keyword)))?;
+            writer.write_event(Event::End(BytesEnd::new("rdf:li")))?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("rdf:Bag")))?;
+        writer.write_event(Event::End(BytesEnd::new(SUBJECT)))?;
+
+        Ok(())
+    }
+}
+
+fn qname(e: &BytesStart) -> String {
+    String::from_utf8_lossy(e.name().as_ref()).into_owned()
+}
+
+fn is_top_level_description(name: &str, stack: &[String]) -> bool {
+    name == "rdf:Description" && stack.last().is_some_and(|parent| parent == "rdf:RDF")
+}
+
+fn is_managed_property(name: &str, stack: &[String]) -> bool {
+    let n = stack.len();
+    MANAGED.contains(&name)
+        && n >= 2
+        && stack[n - 1] == "rdf:Description"
+        && stack[n - 2] == "rdf:RDF"
+}
+
+// XMP writes coordinates as `DDD,MM.mmmmk` or `DDD,MM,SSk` where k is the direction
+fn parse_coordinate(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let direction = value.chars().last()?;
+    let (sign, number) = match direction {
+        'N' | 'E' => (1.0, &value[..value.len() - 1]),
+        'S' | 'W' => (-1.0, &value[..value.len() - 1]),
+        _ => (1.0, value),
+    };
+
+    let parts: Vec<f64> = number
+        .split(',')
+        .map(|part| part.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let degrees = match parts.as_slice() {
+        [d] => *d,
+        [d, m] => d + m / 60.0,
+        [d, m, s] => d + m / 60.0 + s / 3600.0,
+        _ => return None,
+    };
+
+    Some(sign * degrees)
+}
+
+fn format_coordinate(value: f64, positive: char, negative: char) -> String {
+    let direction = if value < 0.0 { negative } else { positive };
+    let value = value.abs();
+    let degrees = value.trunc();
+    let minutes = (value - degrees) * 60.0;
+
+    format!("{},{:.6}{}", degrees as u32, minutes, direction)
+}
+
+// tests ------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let metadata = XmpMetadata::read(Path::new("test-data/01/house.xmp")).unwrap();
+
+        assert_eq!(
+            metadata.date_time_original.as_deref(),
+            Some("2017-03-12T17:50:35.400")
+        );
+
+        // The location of the shot, not of the nested LocationShown
+        let gps = metadata.gps.unwrap();
+        assert!((gps.latitude - (55.0 + 57.6574 / 60.0)).abs() < 1e-9);
+        assert!((gps.longitude + (3.0 + 16.8679 / 60.0)).abs() < 1e-9);
+        assert!(metadata.keywords.is_empty());
+    }
+
+    #[test]
+    fn test_update_preserves_other_properties() {
+        let original = fs::read_to_string("test-data/02/face-right-2.xmp").unwrap();
+        let metadata = XmpMetadata {
+            date_time_original: Some("2009-02-25T16:17:08.000".to_string()),
+            make: Some("Panasonic".to_string()),
+            model: None,
+            gps: Some(GpsPosition {
+                latitude: -33.8568,
+                longitude: 151.2153,
+            }),
+            keywords: vec!["Sydney".to_string(), "Opera & House".to_string()],
+        };
+
+        let updated = metadata.update(&original).unwrap();
+
+        assert!(updated.contains(r#"tiff:Orientation="6""#));
+        assert!(updated.contains("<rdf:li>80</rdf:li>"));
+        assert_eq!(updated.matches(DATE_TIME_ORIGINAL).count(), 1);
+        assert_eq!(
+            XmpMetadata::parse(&updated).unwrap().keywords,
+            metadata.keywords
+        );
+
+        let read_back = XmpMetadata::parse(&updated).unwrap();
+        assert_eq!(read_back.make, metadata.make);
+        let gps = read_back.gps.unwrap();
+        assert!((gps.latitude + 33.8568).abs() < 1e-6);
+        assert!((gps.longitude - 151.2153).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_update_replaces_keywords() {
+        let first = XmpMetadata {
+            keywords: vec!["a".to_string(), "b".to_string()],
+            ..XmpMetadata::default()
+        };
+        let second = XmpMetadata {
+            keywords: vec!["c".to_string()],
+            ..XmpMetadata::default()
+        };
+
+        let xml = second
+            .update(&first.update(EMPTY_SIDECAR).unwrap())
+            .unwrap();
+
+        assert_eq!(XmpMetadata::parse(&xml).unwrap(), second);
+    }
+
+    fn packet(properties: &str) -> String {
+        format!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/" {properties}/>
+ </rdf:RDF>
+</x:xmpmeta>"#
+        )
+    }
+
+    #[test]
+    fn test_longitude_before_latitude() {
+        let xml = packet(r#"exif:GPSLongitude="3,30W" exif:GPSLatitude="55,30N""#);
+        let gps = XmpMetadata::parse(&xml).unwrap().gps.unwrap();
+        assert_eq!(gps.latitude, 55.5);
+        assert_eq!(gps.longitude, -3.5);
+    }
+
+    #[test]
+    fn test_latitude_only() {
+        let xml = packet(r#"exif:GPSLatitude="55,30N""#);
+        assert_eq!(XmpMetadata::parse(&xml).unwrap().gps, None);
+    }
+
+    #[test]
+    fn test_coordinates() {
+        assert_eq!(parse_coordinate("3,30W"), Some(-3.5));
+        assert_eq!(parse_coordinate("10,30,36S"), Some(-10.51));
+        assert_eq!(parse_coordinate("12.25"), Some(12.25));
+        assert_eq!(parse_coordinate("north"), None);
+        assert_eq!(format_coordinate(-3.5, 'E', 'W'), "3,30.000000W");
+    }
+
+    #[test]
+    fn test_exif_to_xmp_date() {
+        assert_eq!(
+            exif_to_xmp_date("2017:03:12 17:50:35"),
+            "2017-03-12T17:50:35"
+        );
+    }
+}